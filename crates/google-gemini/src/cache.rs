@@ -0,0 +1,310 @@
+use crate::{
+    decode, error_for_status, GeminiClient, GeminiMessage, GeminiRequest, GeminiSystemInstruction,
+    GeminiTool, GeminiToolConfig, Result,
+};
+use core::time::Duration;
+use serde::{Deserialize, Serialize, Serializer};
+use time::OffsetDateTime;
+
+/// Prompt prefix stored server-side, referenced through [`GeminiRequest::cached_content`]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCachedContent {
+    /// Resource name such as `cachedContents/abc-123`
+    pub name: String,
+    #[serde(default)]
+    pub model: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub create_time: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub update_time: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expire_time: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub usage_metadata: Option<GeminiCachedContentUsage>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCachedContentUsage {
+    #[serde(default)]
+    pub total_token_count: u32,
+}
+
+/// One page of [`GeminiClient::list_cached_contents`]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCachedContentList {
+    #[serde(default)]
+    pub cached_contents: Vec<GeminiCachedContent>,
+    /// Pass to the next `list_cached_contents` call to continue, empty on the last page
+    #[serde(default)]
+    pub next_page_token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCreateCachedContent<'a> {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<&'a GeminiSystemInstruction>,
+    contents: &'a [GeminiMessage],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [GeminiTool],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_config: Option<&'a GeminiToolConfig>,
+    #[serde(serialize_with = "ttl")]
+    ttl: Duration,
+}
+
+#[derive(Serialize)]
+struct GeminiUpdateCachedContent {
+    #[serde(serialize_with = "ttl")]
+    ttl: Duration,
+}
+
+/// Durations are sent as seconds with an `s` suffix
+fn ttl<S: Serializer>(ttl: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{}s", ttl.as_secs()))
+}
+
+impl GeminiClient {
+    /// URL of a cached content resource, `name` may omit the `cachedContents/` prefix
+    fn cached_content_url(&self, name: &str) -> String {
+        let name = name.strip_prefix("cachedContents/").unwrap_or(name);
+
+        self.with_base(&format!("{}/cachedContents/{name}", self.api_version))
+    }
+
+    /// Cache the system instruction, contents and tools of `request` for `ttl`
+    ///
+    /// Requests using the cache must not set those again. The API rejects caches
+    /// below a model-specific minimum token count.
+    pub async fn create_cached_content(
+        &self,
+        request: &GeminiRequest,
+        ttl: Duration,
+    ) -> Result<GeminiCachedContent> {
+        let url = self.with_base(&format!("{}/cachedContents", self.api_version));
+        let query = [("key", &self.api_key)];
+        let body = GeminiCreateCachedContent {
+            model: format!("models/{}", self.model_id(request)),
+            system_instruction: request.system_instruction.as_ref(),
+            contents: &request.contents,
+            tools: &request.tools,
+            tool_config: request.tool_config.as_ref(),
+            ttl,
+        };
+
+        self.retry_policy
+            .run("create cached content", || async {
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .json(&body)
+                    .send()
+                    .await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// Keep the cache called `name` for another `ttl`
+    pub async fn update_cached_content(
+        &self,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<GeminiCachedContent> {
+        let url = self.cached_content_url(name);
+        let query = [("key", self.api_key.as_str()), ("updateMask", "ttl")];
+        let body = GeminiUpdateCachedContent { ttl };
+
+        self.retry_policy
+            .run("update cached content", || async {
+                let response = self
+                    .client
+                    .patch(&url)
+                    .query(&query)
+                    .json(&body)
+                    .send()
+                    .await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// List cached contents, `page_token` comes from the previous page
+    pub async fn list_cached_contents(
+        &self,
+        page_size: Option<u32>,
+        page_token: Option<&str>,
+    ) -> Result<GeminiCachedContentList> {
+        let url = self.with_base(&format!("{}/cachedContents", self.api_version));
+        let mut query = vec![("key", self.api_key.clone())];
+
+        if let Some(page_size) = page_size {
+            query.push(("pageSize", page_size.to_string()));
+        }
+
+        if let Some(page_token) = page_token.filter(|token| !token.is_empty()) {
+            query.push(("pageToken", page_token.into()));
+        }
+
+        self.retry_policy
+            .run("list cached contents", || async {
+                let response = self.client.get(&url).query(&query).send().await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// Metadata of the cache called `name`
+    pub async fn get_cached_content(&self, name: &str) -> Result<GeminiCachedContent> {
+        let url = self.cached_content_url(name);
+        let query = [("key", &self.api_key)];
+
+        self.retry_policy
+            .run("get cached content", || async {
+                let response = self.client.get(&url).query(&query).send().await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// Delete the cache called `name` before it expires
+    pub async fn delete_cached_content(&self, name: &str) -> Result<()> {
+        let url = self.cached_content_url(name);
+        let query = [("key", &self.api_key)];
+
+        self.retry_policy
+            .run("delete cached content", || async {
+                let response = self.client.delete(&url).query(&query).send().await?;
+
+                error_for_status(response).await?;
+
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{client, MockResponse, MockServer};
+    use crate::{GeminiRole, GeminiSystemPart, Part};
+    use serde_json::json;
+
+    fn cached_content() -> serde_json::Value {
+        json!({
+            "name": "cachedContents/abc",
+            "model": "models/gemini-2.0-flash-exp",
+            "createTime": "2024-12-20T10:00:00.123456Z",
+            "expireTime": "2024-12-20T11:00:00.123456Z",
+            "usageMetadata": {"totalTokenCount": 40000},
+        })
+    }
+
+    #[tokio::test]
+    async fn create_cached_content() {
+        let server = MockServer::start([MockResponse::json(200, cached_content())]).await;
+        let request = GeminiRequest {
+            system_instruction: Some(GeminiSystemInstruction {
+                parts: vec![GeminiSystemPart {
+                    text: "be nice".into(),
+                }],
+            }),
+            contents: vec![GeminiMessage::new(GeminiRole::User, vec![Part::from("hi")])],
+            ..Default::default()
+        };
+
+        let cache = client(&server)
+            .create_cached_content(&request, Duration::from_secs(300))
+            .await
+            .unwrap();
+
+        assert_eq!(cache.name, "cachedContents/abc");
+        assert_eq!(cache.usage_metadata.unwrap().total_token_count, 40000);
+        assert!(cache.expire_time > cache.create_time);
+
+        let requests = server.requests();
+        let body = requests[0].json();
+
+        assert_eq!(requests[0].target, "/v1beta/cachedContents?key=key");
+        assert_eq!(body["model"], "models/gemini-2.0-flash-exp");
+        assert_eq!(body["ttl"], "300s");
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "be nice");
+        assert_eq!(body["contents"][0]["parts"][0]["text"], "hi");
+        assert!(body.get("tools").is_none());
+    }
+
+    #[tokio::test]
+    async fn update_list_and_delete() {
+        let server = MockServer::start([
+            MockResponse::json(200, cached_content()),
+            MockResponse::json(
+                200,
+                json!({"cachedContents": [cached_content()], "nextPageToken": "next"}),
+            ),
+            MockResponse::json(200, json!({})),
+        ])
+        .await;
+
+        let client = client(&server);
+
+        client
+            .update_cached_content("cachedContents/abc", Duration::from_secs(600))
+            .await
+            .unwrap();
+
+        let page = client.list_cached_contents(None, None).await.unwrap();
+
+        assert_eq!(page.cached_contents.len(), 1);
+        assert_eq!(page.next_page_token, "next");
+
+        client.delete_cached_content("abc").await.unwrap();
+
+        let requests = server.requests();
+
+        assert_eq!(requests[0].method, "PATCH");
+        assert_eq!(
+            requests[0].target,
+            "/v1beta/cachedContents/abc?key=key&updateMask=ttl"
+        );
+        assert_eq!(requests[0].json(), json!({"ttl": "600s"}));
+        assert_eq!(requests[1].target, "/v1beta/cachedContents?key=key");
+        assert_eq!(requests[2].method, "DELETE");
+        assert_eq!(requests[2].target, "/v1beta/cachedContents/abc?key=key");
+    }
+
+    #[tokio::test]
+    async fn generate_with_cached_content() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "hi"}]}}]}),
+        )])
+        .await;
+
+        let request = GeminiRequest {
+            cached_content: Some("cachedContents/abc".into()),
+            contents: vec![GeminiMessage::new(
+                GeminiRole::User,
+                vec![Part::from("hello")],
+            )],
+            ..Default::default()
+        };
+
+        client(&server).generate(request).await.unwrap();
+
+        let body = server.requests()[0].json();
+
+        assert_eq!(body["cached_content"], "cachedContents/abc");
+    }
+}
@@ -12,6 +12,7 @@ pub struct TextPart {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InlineDataPart {
+    #[serde(alias = "mimeType")]
     pub mime_type: String,
     pub data: String,
 }
@@ -19,6 +20,7 @@ pub struct InlineDataPart {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FunctionCallPart {
     pub name: String,
+    #[serde(default)]
     pub args: Value,
 }
 
@@ -30,7 +32,9 @@ pub struct FunctionResponsePart {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FileDataPart {
+    #[serde(alias = "mimeType")]
     pub mime_type: String,
+    #[serde(alias = "fileUri")]
     pub file_uri: String,
 }
 
@@ -72,11 +76,17 @@ pub enum Part {
 #[derive(Clone, Debug, Deserialize, From, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TaggedPart {
+    #[serde(alias = "inlineData")]
     InlineData(InlineDataPart),
+    #[serde(alias = "functionCall")]
     FunctionCall(FunctionCallPart),
+    #[serde(alias = "functionResponse")]
     FunctionResponse(FunctionResponsePart),
+    #[serde(alias = "fileData")]
     FileData(FileDataPart),
+    #[serde(alias = "executableCode")]
     ExecutableCode(ExecutableCodePart),
+    #[serde(alias = "codeExecutionResult")]
     CodeExecutionResult(CodeExecutionResultPart),
 }
 
@@ -0,0 +1,315 @@
+use crate::{decode, GeminiClient, Part, Result};
+use serde::{Deserialize, Serialize};
+
+/// Most requests `batchEmbedContents` accepts at once
+pub const MAX_EMBED_BATCH_SIZE: usize = 100;
+
+/// What an embedding will be used for, lets the model optimize for it
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiTaskType {
+    /// Search query, compared against [`Self::RetrievalDocument`] embeddings
+    RetrievalQuery,
+    /// Document to be searched, may have a title
+    RetrievalDocument,
+    SemanticSimilarity,
+    Classification,
+    Clustering,
+    QuestionAnswering,
+    FactVerification,
+    #[serde(other)]
+    TaskTypeUnspecified,
+}
+
+/// Content to embed
+#[derive(Clone, Debug, Default)]
+pub struct GeminiEmbedRequest {
+    pub parts: Vec<Part>,
+    pub task_type: Option<GeminiTaskType>,
+    /// Only used with [`GeminiTaskType::RetrievalDocument`]
+    pub title: Option<String>,
+    /// Truncate the embedding to this many dimensions
+    pub output_dimensionality: Option<u32>,
+}
+
+impl GeminiEmbedRequest {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            parts: vec![Part::from(text.into())],
+            ..Default::default()
+        }
+    }
+
+    pub fn task_type(mut self, task_type: GeminiTaskType) -> Self {
+        self.task_type = Some(task_type);
+        self
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn output_dimensionality(mut self, dimensions: u32) -> Self {
+        self.output_dimensionality = Some(dimensions);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct GeminiEmbedding {
+    #[serde(default)]
+    pub values: Vec<f32>,
+}
+
+impl GeminiEmbedding {
+    /// Cosine similarity between `-1.0` and `1.0`, `0.0` if either embedding is empty
+    pub fn cosine_similarity(&self, other: &Self) -> f32 {
+        let (mut dot, mut left, mut right) = (0.0, 0.0, 0.0);
+
+        for (a, b) in self.values.iter().zip(&other.values) {
+            dot += a * b;
+            left += a * a;
+            right += b * b;
+        }
+
+        if left == 0.0 || right == 0.0 {
+            return 0.0;
+        }
+
+        dot / (left.sqrt() * right.sqrt())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiEmbedContentRequest<'a> {
+    model: String,
+    content: GeminiEmbedContent<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_type: Option<GeminiTaskType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_dimensionality: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct GeminiEmbedContent<'a> {
+    parts: &'a [Part],
+}
+
+#[derive(Serialize)]
+struct GeminiBatchEmbedRequest<'a> {
+    requests: Vec<GeminiEmbedContentRequest<'a>>,
+}
+
+#[derive(Deserialize)]
+struct GeminiEmbedResponse {
+    embedding: GeminiEmbedding,
+}
+
+#[derive(Deserialize)]
+struct GeminiBatchEmbedResponse {
+    #[serde(default)]
+    embeddings: Vec<GeminiEmbedding>,
+}
+
+impl GeminiClient {
+    fn embed_url(&self, method: &str) -> String {
+        self.with_base(&format!(
+            "{}/models/{}:{method}",
+            self.api_version,
+            self.embedding_model_id()
+        ))
+    }
+
+    fn embedding_model_id(&self) -> &str {
+        let model = &self.embedding_model;
+
+        model.strip_prefix("models/").unwrap_or(model)
+    }
+
+    fn embed_body<'a>(&self, request: &'a GeminiEmbedRequest) -> GeminiEmbedContentRequest<'a> {
+        GeminiEmbedContentRequest {
+            model: format!("models/{}", self.embedding_model_id()),
+            content: GeminiEmbedContent {
+                parts: &request.parts,
+            },
+            task_type: request.task_type,
+            title: request.title.as_deref(),
+            output_dimensionality: request.output_dimensionality,
+        }
+    }
+
+    /// Embed a single content with the embedding model
+    pub async fn embed_content(&self, request: &GeminiEmbedRequest) -> Result<GeminiEmbedding> {
+        let url = self.embed_url("embedContent");
+        let query = [("key", &self.api_key)];
+        let body = self.embed_body(request);
+
+        let response = self
+            .retry_policy
+            .run("embed content", || async {
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .json(&body)
+                    .send()
+                    .await?;
+
+                decode::<GeminiEmbedResponse>(response).await
+            })
+            .await?;
+
+        Ok(response.embedding)
+    }
+
+    /// Embed any number of contents, in batches of [`MAX_EMBED_BATCH_SIZE`]
+    ///
+    /// Embeddings are returned in the order of `requests`.
+    pub async fn batch_embed_contents(
+        &self,
+        requests: &[GeminiEmbedRequest],
+    ) -> Result<Vec<GeminiEmbedding>> {
+        let url = self.embed_url("batchEmbedContents");
+        let query = [("key", &self.api_key)];
+        let mut embeddings = Vec::with_capacity(requests.len());
+
+        for batch in requests.chunks(MAX_EMBED_BATCH_SIZE) {
+            let body = GeminiBatchEmbedRequest {
+                requests: batch
+                    .iter()
+                    .map(|request| self.embed_body(request))
+                    .collect(),
+            };
+
+            let response = self
+                .retry_policy
+                .run("batch embed contents", || async {
+                    let response = self
+                        .client
+                        .post(&url)
+                        .query(&query)
+                        .json(&body)
+                        .send()
+                        .await?;
+
+                    decode::<GeminiBatchEmbedResponse>(response).await
+                })
+                .await?;
+
+            embeddings.extend(response.embeddings);
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Embed `texts` for `task_type`, batching as needed
+    pub async fn embed_texts<S: AsRef<str>>(
+        &self,
+        texts: &[S],
+        task_type: GeminiTaskType,
+    ) -> Result<Vec<GeminiEmbedding>> {
+        let requests = texts
+            .iter()
+            .map(|text| GeminiEmbedRequest::new(text.as_ref()).task_type(task_type))
+            .collect::<Vec<_>>();
+
+        self.batch_embed_contents(&requests).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{client, MockResponse, MockServer};
+    use serde_json::json;
+
+    fn embeddings(count: usize) -> MockResponse {
+        let embeddings = (0..count)
+            .map(|index| json!({"values": [index as f32, 1.0]}))
+            .collect::<Vec<_>>();
+
+        MockResponse::json(200, json!({ "embeddings": embeddings }))
+    }
+
+    #[tokio::test]
+    async fn embed_content() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            json!({"embedding": {"values": [0.5, -0.25]}}),
+        )])
+        .await;
+
+        let request = GeminiEmbedRequest::new("mari's cat")
+            .task_type(GeminiTaskType::RetrievalDocument)
+            .title("pets")
+            .output_dimensionality(2);
+
+        let embedding = client(&server).embed_content(&request).await.unwrap();
+
+        assert_eq!(embedding.values, [0.5, -0.25]);
+
+        let requests = server.requests();
+
+        assert_eq!(
+            requests[0].target,
+            "/v1beta/models/text-embedding-004:embedContent?key=key"
+        );
+        assert_eq!(
+            requests[0].json(),
+            json!({
+                "model": "models/text-embedding-004",
+                "content": {"parts": [{"text": "mari's cat"}]},
+                "taskType": "RETRIEVAL_DOCUMENT",
+                "title": "pets",
+                "outputDimensionality": 2,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_large_inputs() {
+        let server = MockServer::start([embeddings(100), embeddings(50)]).await;
+        let texts = (0..150).map(|index| index.to_string()).collect::<Vec<_>>();
+        let client = client(&server).with_embedding_model("models/embedding-001");
+
+        let embeddings = client
+            .embed_texts(&texts, GeminiTaskType::Clustering)
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings.len(), 150);
+        assert_eq!(embeddings[100].values, [0.0, 1.0]);
+
+        let requests = server.requests();
+        let first = requests[0].json();
+        let second = requests[1].json();
+
+        assert_eq!(
+            requests[0].target,
+            "/v1beta/models/embedding-001:batchEmbedContents?key=key"
+        );
+        assert_eq!(first["requests"].as_array().unwrap().len(), 100);
+        assert_eq!(second["requests"].as_array().unwrap().len(), 50);
+        assert_eq!(second["requests"][0]["content"]["parts"][0]["text"], "100");
+        assert_eq!(second["requests"][0]["model"], "models/embedding-001");
+        assert_eq!(second["requests"][0]["taskType"], "CLUSTERING");
+    }
+
+    #[test]
+    fn cosine_similarity() {
+        let embedding = |values: &[f32]| GeminiEmbedding {
+            values: values.to_vec(),
+        };
+
+        let a = embedding(&[1.0, 0.0]);
+
+        assert_eq!(a.cosine_similarity(&embedding(&[2.0, 0.0])), 1.0);
+        assert_eq!(a.cosine_similarity(&embedding(&[0.0, 3.0])), 0.0);
+        assert_eq!(a.cosine_similarity(&embedding(&[-1.0, 0.0])), -1.0);
+        assert_eq!(a.cosine_similarity(&embedding(&[])), 0.0);
+    }
+}
@@ -0,0 +1,200 @@
+use core::time::Duration;
+use derive_more::{Display, Error, From};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
+
+pub type Result<T, E = GeminiError> = core::result::Result<T, E>;
+
+/// Everything that can go wrong talking to the Gemini API
+#[derive(Debug, Display, Error, From)]
+pub enum GeminiError {
+    /// Request could not be sent or the response body could not be read
+    #[display("http: {_0}")]
+    #[from]
+    Http(reqwest::Error),
+    /// API answered with a non-success status
+    #[display("{status}: {error}")]
+    Status {
+        status: StatusCode,
+        #[error(not(source))]
+        error: GeminiApiError,
+        /// Delay requested by the `Retry-After` header
+        retry_after: Option<Duration>,
+    },
+    /// Response body did not match the expected shape
+    #[display("decode: {source}")]
+    Decode {
+        source: serde_json::Error,
+        body: String,
+    },
+    /// Prompt was rejected before any candidate was generated
+    #[display("blocked: {reason}")]
+    Blocked {
+        #[error(not(source))]
+        reason: String,
+    },
+    /// Upload content could not be read
+    #[display("io: {_0}")]
+    #[from]
+    Io(std::io::Error),
+    /// Server resumed an upload at an offset we no longer have the bytes for
+    #[display("cannot resume upload at {received}, buffered from {offset}")]
+    UploadOffset { received: u64, offset: u64 },
+    /// Uploaded file ended up in the `FAILED` state
+    #[display("processing {name} failed: {error}")]
+    ProcessingFailed {
+        name: String,
+        #[error(not(source))]
+        error: GeminiApiError,
+    },
+    /// Uploaded file was still processing when the poll timeout ran out
+    #[display("{name} still processing after {timeout:?}")]
+    ProcessingTimeout { name: String, timeout: Duration },
+    /// Expected response header was absent
+    #[display("missing expected {_0}")]
+    MissingHeader(#[error(not(source))] &'static str),
+}
+
+impl GeminiError {
+    /// HTTP status, if the API answered at all
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Http(error) => error.status(),
+            Self::Status { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Google error payload, if the API answered with one
+    pub fn api_error(&self) -> Option<&GeminiApiError> {
+        match self {
+            Self::Status { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn decode(source: serde_json::Error, body: &[u8]) -> Self {
+        Self::Decode {
+            source,
+            body: String::from_utf8_lossy(body).into_owned(),
+        }
+    }
+
+    /// How long the server asked us to wait before retrying, from `Retry-After` or `RetryInfo`
+    pub fn retry_delay(&self) -> Option<Duration> {
+        match self {
+            Self::Status {
+                error, retry_after, ..
+            } => retry_after.or_else(|| error.retry_delay()),
+            _ => None,
+        }
+    }
+
+    /// Build from a non-success response body, keeping the raw text if it is not a Google error
+    pub(crate) fn from_status(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        let error = match serde_json::from_slice::<GeminiErrorBody>(body) {
+            Ok(body) => body.error,
+            Err(_error) => GeminiApiError {
+                code: status.as_u16(),
+                message: String::from_utf8_lossy(body).into_owned(),
+                status: String::new(),
+                details: Vec::new(),
+            },
+        };
+
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+
+        Self::Status {
+            status,
+            error,
+            retry_after,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GeminiErrorBody {
+    error: GeminiApiError,
+}
+
+/// `error` object of a Google API error response
+#[derive(Clone, Debug, Default, Deserialize, Display)]
+#[display("{status} {message}")]
+pub struct GeminiApiError {
+    #[serde(default)]
+    pub code: u16,
+    #[serde(default)]
+    pub message: String,
+    /// Canonical code such as `RESOURCE_EXHAUSTED` or `INVALID_ARGUMENT`
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub details: Vec<Value>,
+}
+
+impl GeminiApiError {
+    /// `retryDelay` of a `google.rpc.RetryInfo` detail
+    pub fn retry_delay(&self) -> Option<Duration> {
+        self.details
+            .iter()
+            .filter(|detail| {
+                detail.get("@type").and_then(Value::as_str)
+                    == Some("type.googleapis.com/google.rpc.RetryInfo")
+            })
+            .find_map(|detail| detail.get("retryDelay")?.as_str())
+            .and_then(parse_duration)
+    }
+}
+
+/// Parse a protobuf JSON `Duration` such as `"3s"` or `"0.5s"`
+fn parse_duration(value: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(value.strip_suffix('s')?.parse().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_from_details() {
+        let error = GeminiError::from_status(
+            StatusCode::TOO_MANY_REQUESTS,
+            &HeaderMap::new(),
+            br#"{"error": {"code": 429, "status": "RESOURCE_EXHAUSTED", "details": [
+                {"@type": "type.googleapis.com/google.rpc.QuotaFailure"},
+                {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "1.5s"}
+            ]}}"#,
+        );
+
+        assert_eq!(error.retry_delay(), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn retry_after_header_wins() {
+        let mut headers = HeaderMap::new();
+
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+
+        let error = GeminiError::from_status(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &headers,
+            br#"{"error": {"details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "1s"}]}}"#,
+        );
+
+        assert_eq!(error.retry_delay(), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("3s"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_duration("0.25s"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3"), None);
+        assert_eq!(parse_duration("-1s"), None);
+    }
+}
@@ -0,0 +1,768 @@
+use crate::{decode, error_for_status, GeminiApiError, GeminiClient, GeminiError, Result};
+use core::time::Duration;
+use reqwest::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::io;
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::Instant;
+
+pub(crate) const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Every chunk but the last has to be a multiple of this
+pub(crate) const UPLOAD_CHUNK_GRANULARITY: usize = 256 * 1024;
+
+const X_GOOG_UPLOAD_COMMAND: HeaderName = HeaderName::from_static("x-goog-upload-command");
+const X_GOOG_UPLOAD_HEADER_CONTENT_LENGTH: HeaderName =
+    HeaderName::from_static("x-goog-upload-header-content-length");
+const X_GOOG_UPLOAD_HEADER_CONTENT_TYPE: HeaderName =
+    HeaderName::from_static("x-goog-upload-header-content-type");
+const X_GOOG_UPLOAD_OFFSET: HeaderName = HeaderName::from_static("x-goog-upload-offset");
+const X_GOOG_UPLOAD_PROTOCOL: HeaderName = HeaderName::from_static("x-goog-upload-protocol");
+const X_GOOG_UPLOAD_SIZE_RECEIVED: HeaderName =
+    HeaderName::from_static("x-goog-upload-size-received");
+const X_GOOG_UPLOAD_STATUS: HeaderName = HeaderName::from_static("x-goog-upload-status");
+const X_GOOG_UPLOAD_URL: HeaderName = HeaderName::from_static("x-goog-upload-url");
+
+const QUERY: HeaderValue = HeaderValue::from_static("query");
+const RESUMABLE: HeaderValue = HeaderValue::from_static("resumable");
+const START: HeaderValue = HeaderValue::from_static("start");
+const UPLOAD: HeaderValue = HeaderValue::from_static("upload");
+const UPLOAD_FINALIZE: HeaderValue = HeaderValue::from_static("upload, finalize");
+
+#[derive(Serialize)]
+struct GeminiCreateFile<'a> {
+    file: GeminiFile<'a>,
+}
+
+#[derive(Serialize)]
+struct GeminiFile<'a> {
+    display_name: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiFileResponse {
+    file: GeminiFileMetadata,
+}
+
+/// Progress of an interrupted upload according to the server
+enum UploadStatus {
+    Active { received: u64 },
+    Final(Box<GeminiFileMetadata>),
+}
+
+/// File stored through the Files API
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiFileMetadata {
+    /// Resource name such as `files/abc-123`
+    pub name: String,
+    #[serde(default)]
+    pub display_name: String,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default, deserialize_with = "int64")]
+    pub size_bytes: u64,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub create_time: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub update_time: Option<OffsetDateTime>,
+    /// When the file gets deleted, files are kept for 48 hours
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expiration_time: Option<OffsetDateTime>,
+    /// Base64 encoded SHA-256 of the uploaded bytes
+    #[serde(default)]
+    pub sha256_hash: String,
+    pub uri: String,
+    #[serde(default)]
+    pub state: GeminiFileState,
+    /// Why processing failed, if it did
+    #[serde(default)]
+    pub error: Option<GeminiApiError>,
+}
+
+impl GeminiFileMetadata {
+    /// Whether the file is gone at `now`, files without an expiration never expire
+    pub fn is_expired_at(&self, now: OffsetDateTime) -> bool {
+        self.expiration_time
+            .is_some_and(|expiration_time| expiration_time <= now)
+    }
+}
+
+/// Processing state of an uploaded file
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiFileState {
+    /// Still being processed, not usable in requests yet
+    Processing,
+    /// Ready to be used in requests
+    Active,
+    /// Processing failed, see [`GeminiFileMetadata::error`]
+    Failed,
+    #[default]
+    #[serde(other)]
+    StateUnspecified,
+}
+
+/// How long and how often to wait for uploaded files to finish processing
+#[derive(Clone, Debug)]
+pub struct PollPolicy {
+    /// Delay before the first poll, doubled after every further one
+    pub interval: Duration,
+    /// Upper bound for the delay between polls
+    pub max_interval: Duration,
+    /// Give up once a file has been processing for this long
+    pub timeout: Duration,
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// One page of [`GeminiClient::list_files`]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiFileList {
+    #[serde(default)]
+    pub files: Vec<GeminiFileMetadata>,
+    /// Pass to the next `list_files` call to continue, empty on the last page
+    #[serde(default)]
+    pub next_page_token: String,
+}
+
+/// int64 fields are sent as JSON strings
+fn int64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Int64 {
+        Number(u64),
+        String(String),
+    }
+
+    match Int64::deserialize(deserializer)? {
+        Int64::Number(number) => Ok(number),
+        Int64::String(string) => string.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+impl GeminiClient {
+    /// Start a resumable upload, returns the URL to upload the content to
+    pub async fn create_file(
+        &self,
+        file_name: &str,
+        content_length: u64,
+        content_type: &str,
+    ) -> Result<String> {
+        let url = self.with_base(&format!("upload/{}/files", self.api_version));
+        let query = [("key", &self.api_key)];
+        let request = GeminiCreateFile {
+            file: GeminiFile {
+                display_name: file_name,
+            },
+        };
+
+        let response = self
+            .retry_policy
+            .run("create file", || async {
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .header(X_GOOG_UPLOAD_PROTOCOL, RESUMABLE)
+                    .header(X_GOOG_UPLOAD_COMMAND, START)
+                    .header(X_GOOG_UPLOAD_HEADER_CONTENT_LENGTH, content_length)
+                    .header(X_GOOG_UPLOAD_HEADER_CONTENT_TYPE, content_type)
+                    .json(&request)
+                    .send()
+                    .await?;
+
+                error_for_status(response).await
+            })
+            .await?;
+
+        let url = response
+            .headers()
+            .get(X_GOOG_UPLOAD_URL)
+            .and_then(|value| value.to_str().map(String::from).ok())
+            .ok_or(GeminiError::MissingHeader("x-goog-upload-url"))?;
+
+        Ok(url)
+    }
+
+    /// Upload `bytes` to a `url` from [`Self::create_file`]
+    pub async fn upload_file(&self, url: &str, bytes: &[u8]) -> Result<GeminiFileMetadata> {
+        self.upload_file_from_reader(url, bytes.len() as u64, bytes)
+            .await
+    }
+
+    /// Upload `content_length` bytes from `reader` to a `url` from [`Self::create_file`]
+    ///
+    /// Only one chunk is buffered at a time. When a chunk fails the server is asked how
+    /// much it received and the upload continues from there. Fails if `reader` doesn't
+    /// hold exactly `content_length` bytes.
+    pub async fn upload_file_from_reader(
+        &self,
+        url: &str,
+        content_length: u64,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<GeminiFileMetadata> {
+        let mut chunk = Vec::with_capacity(self.upload_chunk_size.min(content_length as usize));
+        // upload offset of the first byte in `chunk`
+        let mut offset = 0;
+        let mut retry = 1;
+
+        let file = loop {
+            let remaining = content_length - offset - chunk.len() as u64;
+            let wanted = (self.upload_chunk_size.saturating_sub(chunk.len()) as u64).min(remaining);
+            let read = (&mut reader).take(wanted).read_to_end(&mut chunk).await?;
+
+            if (read as u64) < wanted {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+
+            let end = offset + chunk.len() as u64;
+
+            if end == content_length && reader.read(&mut [0]).await? > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("reader is longer than {content_length} bytes"),
+                )
+                .into());
+            }
+
+            let error = match self
+                .upload_chunk(url, offset, end == content_length, &chunk)
+                .await
+            {
+                Ok(Some(file)) => break file,
+                Ok(None) => {
+                    offset = end;
+                    retry = 1;
+                    chunk.clear();
+
+                    continue;
+                }
+                Err(error) => error,
+            };
+
+            let Some(delay) = self.retry_policy.upload_delay(retry, &error) else {
+                return Err(error);
+            };
+
+            tracing::warn!(
+                "upload at {offset} failed, resuming in {delay:?} (attempt {retry}): {error}"
+            );
+
+            tokio::time::sleep(delay).await;
+
+            retry += 1;
+
+            match self.query_upload(url).await? {
+                UploadStatus::Final(file) => break *file,
+                UploadStatus::Active { received } if (offset..=end).contains(&received) => {
+                    chunk.drain(..(received - offset) as usize);
+                    offset = received;
+                }
+                UploadStatus::Active { received } => {
+                    return Err(GeminiError::UploadOffset { received, offset });
+                }
+            }
+        };
+
+        tracing::debug!("uploaded file: {file:#?}");
+
+        self.wait_for_processing(file).await
+    }
+
+    /// Send one chunk, returns the file once the upload is finalized
+    async fn upload_chunk(
+        &self,
+        url: &str,
+        offset: u64,
+        finalize: bool,
+        chunk: &[u8],
+    ) -> Result<Option<GeminiFileMetadata>> {
+        let command = if finalize { UPLOAD_FINALIZE } else { UPLOAD };
+
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_LENGTH, chunk.len())
+            .header(X_GOOG_UPLOAD_OFFSET, offset)
+            .header(X_GOOG_UPLOAD_COMMAND, command)
+            .body(chunk.to_vec())
+            .send()
+            .await?;
+
+        if !finalize {
+            error_for_status(response).await?;
+
+            return Ok(None);
+        }
+
+        Ok(Some(decode::<GeminiFileResponse>(response).await?.file))
+    }
+
+    /// Ask how many bytes of an interrupted upload arrived
+    async fn query_upload(&self, url: &str) -> Result<UploadStatus> {
+        self.retry_policy
+            .run("query upload", || async {
+                let response = self
+                    .client
+                    .post(url)
+                    .header(CONTENT_LENGTH, 0)
+                    .header(X_GOOG_UPLOAD_COMMAND, QUERY)
+                    .send()
+                    .await?;
+
+                let response = error_for_status(response).await?;
+                let headers = response.headers();
+
+                if headers.get(X_GOOG_UPLOAD_STATUS) == Some(&HeaderValue::from_static("final")) {
+                    let response = decode::<GeminiFileResponse>(response).await?;
+
+                    return Ok(UploadStatus::Final(Box::new(response.file)));
+                }
+
+                let received = headers
+                    .get(X_GOOG_UPLOAD_SIZE_RECEIVED)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .ok_or(GeminiError::MissingHeader("x-goog-upload-size-received"))?;
+
+                Ok(UploadStatus::Active { received })
+            })
+            .await
+    }
+
+    /// Poll `file` until it is active, fails or the poll timeout is reached
+    async fn wait_for_processing(
+        &self,
+        mut file: GeminiFileMetadata,
+    ) -> Result<GeminiFileMetadata> {
+        let policy = &self.poll_policy;
+        let deadline = Instant::now() + policy.timeout;
+        let mut interval = policy.interval;
+
+        while file.state == GeminiFileState::Processing {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(GeminiError::ProcessingTimeout {
+                    name: file.name,
+                    timeout: policy.timeout,
+                });
+            }
+
+            tokio::time::sleep(interval.min(deadline - now)).await;
+
+            interval = interval.saturating_mul(2).min(policy.max_interval);
+
+            file = self
+                .get_file(&file.name)
+                .await
+                .inspect_err(|error| tracing::error!("processing file: {error}"))?;
+
+            tracing::debug!("processing file response: {file:#?}");
+        }
+
+        if file.state == GeminiFileState::Failed {
+            return Err(GeminiError::ProcessingFailed {
+                name: file.name,
+                error: file.error.unwrap_or_default(),
+            });
+        }
+
+        Ok(file)
+    }
+
+    /// URL of a file resource, `name` may omit the `files/` prefix
+    fn file_url(&self, name: &str) -> String {
+        let name = name.strip_prefix("files/").unwrap_or(name);
+
+        self.with_base(&format!("{}/files/{name}", self.api_version))
+    }
+
+    /// List uploaded files, `page_token` comes from the previous page
+    pub async fn list_files(
+        &self,
+        page_size: Option<u32>,
+        page_token: Option<&str>,
+    ) -> Result<GeminiFileList> {
+        let url = self.with_base(&format!("{}/files", self.api_version));
+        let mut query = vec![("key", self.api_key.clone())];
+
+        if let Some(page_size) = page_size {
+            query.push(("pageSize", page_size.to_string()));
+        }
+
+        if let Some(page_token) = page_token.filter(|token| !token.is_empty()) {
+            query.push(("pageToken", page_token.into()));
+        }
+
+        self.retry_policy
+            .run("list files", || async {
+                let response = self.client.get(&url).query(&query).send().await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// Metadata of the file called `name`
+    pub async fn get_file(&self, name: &str) -> Result<GeminiFileMetadata> {
+        let url = self.file_url(name);
+        let query = [("key", &self.api_key)];
+
+        self.retry_policy
+            .run("get file", || async {
+                let response = self.client.get(&url).query(&query).send().await?;
+
+                decode(response).await
+            })
+            .await
+    }
+
+    /// Delete the file called `name` before it expires
+    pub async fn delete_file(&self, name: &str) -> Result<()> {
+        let url = self.file_url(name);
+        let query = [("key", &self.api_key)];
+
+        self.retry_policy
+            .run("delete file", || async {
+                let response = self.client.delete(&url).query(&query).send().await?;
+
+                error_for_status(response).await?;
+
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{client, retrying_client, MockResponse, MockServer};
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    fn file(name: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "displayName": "a.txt",
+            "mimeType": "text/plain",
+            "sizeBytes": "5",
+            "createTime": "2024-12-20T10:00:00.123456Z",
+            "expirationTime": "2024-12-22T10:00:00.123456Z",
+            "sha256Hash": "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=",
+            "uri": format!("https://example.com/v1beta/{name}"),
+            "state": "ACTIVE",
+        })
+    }
+
+    #[tokio::test]
+    async fn file_upload_retries() {
+        let server = MockServer::start([
+            MockResponse::new(500),
+            MockResponse::new(200).header("x-goog-upload-url", "/upload/session"),
+            MockResponse::new(503),
+            MockResponse::new(200)
+                .header("x-goog-upload-status", "active")
+                .header("x-goog-upload-size-received", "0"),
+            MockResponse::json(200, json!({"file": file("files/abc")})),
+        ])
+        .await;
+
+        let client = retrying_client(&server);
+        let url = client.create_file("a.txt", 5, "text/plain").await.unwrap();
+
+        assert_eq!(url, "/upload/session");
+
+        let file = client
+            .upload_file(&(server.base_url() + &url), b"hello")
+            .await
+            .unwrap();
+
+        assert_eq!(file.name, "files/abc");
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 5);
+        assert_eq!(
+            requests[1].header("x-goog-upload-header-content-length"),
+            Some("5")
+        );
+        assert_eq!(requests[3].header("x-goog-upload-command"), Some("query"));
+        assert_eq!(requests[4].target, "/upload/session");
+        assert_eq!(requests[4].body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_resumes() {
+        let server = MockServer::start([
+            MockResponse::new(200),
+            MockResponse::new(503),
+            MockResponse::new(200)
+                .header("x-goog-upload-status", "active")
+                .header("x-goog-upload-size-received", "6"),
+            MockResponse::json(200, json!({"file": file("files/abc")})),
+        ])
+        .await;
+
+        let mut client = retrying_client(&server);
+
+        // below the granularity the builder enforces, to keep the test small
+        client.upload_chunk_size = 4;
+        let url = server.base_url() + "/upload/session";
+        let file = client
+            .upload_file_from_reader(&url, 10, &b"helloworld"[..])
+            .await
+            .unwrap();
+
+        assert_eq!(file.name, "files/abc");
+
+        let chunks = server
+            .requests()
+            .into_iter()
+            .map(|request| {
+                (
+                    request.header("x-goog-upload-command").unwrap().to_string(),
+                    request.header("x-goog-upload-offset").map(String::from),
+                    String::from_utf8(request.body).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let chunk = |command: &str, offset: Option<&str>, body: &str| {
+            (command.into(), offset.map(String::from), body.into())
+        };
+
+        assert_eq!(
+            chunks,
+            [
+                chunk("upload", Some("0"), "hell"),
+                chunk("upload", Some("4"), "owor"),
+                chunk("query", None, ""),
+                chunk("upload, finalize", Some("6"), "orld"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_finished_while_interrupted() {
+        let server = MockServer::start([
+            MockResponse::new(502),
+            MockResponse::json(200, json!({"file": file("files/abc")}))
+                .header("x-goog-upload-status", "final"),
+        ])
+        .await;
+
+        let client = retrying_client(&server);
+        let url = server.base_url() + "/upload/session";
+        let file = client.upload_file(&url, b"hello").await.unwrap();
+
+        assert_eq!(file.name, "files/abc");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_short_reader() {
+        let server = MockServer::start([]).await;
+        let url = server.base_url() + "/upload/session";
+        let error = client(&server)
+            .upload_file_from_reader(&url, 10, &b"hello"[..])
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, GeminiError::Io(_)));
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_long_reader() {
+        let server = MockServer::start([]).await;
+        let url = server.base_url() + "/upload/session";
+        let error = client(&server)
+            .upload_file_from_reader(&url, 5, &b"helloworld"[..])
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, GeminiError::Io(_)));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn chunk_size_granularity() {
+        let client = |size| {
+            GeminiClient::new("key".into())
+                .with_upload_chunk_size(size)
+                .upload_chunk_size
+        };
+
+        assert_eq!(client(0), UPLOAD_CHUNK_GRANULARITY);
+        assert_eq!(client(1), UPLOAD_CHUNK_GRANULARITY);
+        assert_eq!(client(UPLOAD_CHUNK_GRANULARITY), UPLOAD_CHUNK_GRANULARITY);
+        assert_eq!(
+            client(UPLOAD_CHUNK_GRANULARITY + 1),
+            2 * UPLOAD_CHUNK_GRANULARITY
+        );
+    }
+
+    fn polling_client(server: &MockServer) -> GeminiClient {
+        client(server).with_poll_policy(PollPolicy {
+            interval: Duration::from_millis(1),
+            max_interval: Duration::from_millis(4),
+            timeout: Duration::from_millis(100),
+        })
+    }
+
+    fn with_state(state: &str) -> serde_json::Value {
+        let mut file = file("files/abc");
+
+        file["state"] = state.into();
+        file
+    }
+
+    fn processing(state: &str) -> MockResponse {
+        MockResponse::json(200, with_state(state))
+    }
+
+    #[tokio::test]
+    async fn waits_for_processing() {
+        let server = MockServer::start([processing("PROCESSING"), processing("ACTIVE")]).await;
+        let client = polling_client(&server);
+        let file = serde_json::from_value(with_state("PROCESSING")).unwrap();
+
+        let file = client.wait_for_processing(file).await.unwrap();
+        let requests = server.requests();
+
+        assert_eq!(file.state, GeminiFileState::Active);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].target, "/v1beta/files/abc?key=key");
+    }
+
+    #[tokio::test]
+    async fn processing_failed() {
+        let mut failed = with_state("FAILED");
+
+        failed["error"] = json!({"code": 3, "message": "unsupported video codec"});
+
+        let server = MockServer::start([MockResponse::json(200, failed)]).await;
+        let client = polling_client(&server);
+        let file = serde_json::from_value(with_state("PROCESSING")).unwrap();
+
+        let error = client.wait_for_processing(file).await.unwrap_err();
+
+        let GeminiError::ProcessingFailed { name, error } = error else {
+            panic!("expected a processing failure, got {error:?}");
+        };
+
+        assert_eq!(name, "files/abc");
+        assert_eq!(error.message, "unsupported video codec");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn processing_times_out() {
+        let server = MockServer::start((0..64).map(|_| processing("PROCESSING"))).await;
+        let client = polling_client(&server);
+        let file = serde_json::from_value(with_state("PROCESSING")).unwrap();
+
+        let start = Instant::now();
+        let error = client.wait_for_processing(file).await.unwrap_err();
+
+        assert!(matches!(error, GeminiError::ProcessingTimeout { .. }));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // after 1, 3 and 7 ms, every 4 ms up to 99 ms, then once more at the deadline
+        assert_eq!(server.requests().len(), 27);
+    }
+
+    #[tokio::test]
+    async fn list_files() {
+        let server = MockServer::start([
+            MockResponse::json(
+                200,
+                json!({"files": [file("files/a"), file("files/b")], "nextPageToken": "next"}),
+            ),
+            MockResponse::json(200, json!({})),
+        ])
+        .await;
+
+        let client = client(&server);
+        let page = client.list_files(Some(2), None).await.unwrap();
+
+        assert_eq!(page.files.len(), 2);
+        assert_eq!(page.files[1].name, "files/b");
+        assert_eq!(page.next_page_token, "next");
+
+        let page = client
+            .list_files(Some(2), Some(&page.next_page_token))
+            .await
+            .unwrap();
+
+        assert!(page.files.is_empty());
+        assert!(page.next_page_token.is_empty());
+
+        let requests = server.requests();
+
+        assert_eq!(requests[0].target, "/v1beta/files?key=key&pageSize=2");
+        assert_eq!(
+            requests[1].target,
+            "/v1beta/files?key=key&pageSize=2&pageToken=next"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_file() {
+        let server = MockServer::start([MockResponse::json(200, file("files/abc"))]).await;
+        let file = client(&server).get_file("abc").await.unwrap();
+
+        assert_eq!(file.size_bytes, 5);
+        assert_eq!(file.state, GeminiFileState::Active);
+        assert_eq!(file.mime_type, "text/plain");
+
+        let expiration_time = file.expiration_time.unwrap();
+
+        assert!(!file.is_expired_at(expiration_time - time::Duration::SECOND));
+        assert!(file.is_expired_at(expiration_time));
+        assert_eq!(server.requests()[0].target, "/v1beta/files/abc?key=key");
+    }
+
+    #[test]
+    fn failed_file() {
+        let mut failed = file("files/abc");
+
+        failed["state"] = "FAILED".into();
+        failed["error"] = json!({"code": 3, "message": "unsupported video codec"});
+
+        let file = serde_json::from_value::<GeminiFileMetadata>(failed).unwrap();
+
+        assert_eq!(file.state, GeminiFileState::Failed);
+        assert_eq!(file.error.unwrap().message, "unsupported video codec");
+    }
+
+    #[tokio::test]
+    async fn delete_file() {
+        let server = MockServer::start([
+            MockResponse::json(200, json!({})),
+            MockResponse::json(404, json!({"error": {"code": 404, "status": "NOT_FOUND"}})),
+        ])
+        .await;
+
+        let client = client(&server);
+
+        client.delete_file("files/abc").await.unwrap();
+
+        let error = client.delete_file("files/abc").await.unwrap_err();
+        let requests = server.requests();
+
+        assert_eq!(error.status(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(requests[0].method, "DELETE");
+        assert_eq!(requests[0].target, "/v1beta/files/abc?key=key");
+    }
+}
@@ -1,44 +1,104 @@
-use core::time::Duration;
+use self::file::{DEFAULT_UPLOAD_CHUNK_SIZE, UPLOAD_CHUNK_GRANULARITY};
+use self::limit::RateLimiter;
+use self::sse::EventParser;
+use futures_util::Stream;
 use mime::Mime;
-use reqwest::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
-use reqwest::Client;
+use reqwest::{Client, Response};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+pub use self::cache::{GeminiCachedContent, GeminiCachedContentList, GeminiCachedContentUsage};
 pub use self::content::{FileDataPart, Part, TextPart};
+pub use self::embed::{GeminiEmbedRequest, GeminiEmbedding, GeminiTaskType, MAX_EMBED_BATCH_SIZE};
+pub use self::error::{GeminiApiError, GeminiError, Result};
+pub use self::file::{GeminiFileList, GeminiFileMetadata, GeminiFileState, PollPolicy};
+pub use self::limit::{estimate_tokens, RateLimiterMetrics, RateLimits};
+pub use self::retry::RetryPolicy;
+pub use self::schema::{GeminiSchema, GeminiSchemaError, GeminiSchemaType};
+pub use self::tool::{
+    FunctionCallingConfig, FunctionCallingMode, FunctionDeclaration, FunctionRegistry, GeminiTool,
+    GeminiToolConfig, ToolLoopResponse,
+};
 
 extern crate alloc;
 
+mod cache;
 pub mod content;
+mod embed;
+mod error;
+mod file;
+mod limit;
+#[cfg(test)]
+mod mock;
+mod retry;
+mod schema;
+mod sse;
+mod tool;
 
 const BASE_URL: &str = "https://generativelanguage.googleapis.com";
-
-const X_GOOG_UPLOAD_COMMAND: HeaderName = HeaderName::from_static("x-goog-upload-command");
-const X_GOOG_UPLOAD_HEADER_CONTENT_LENGTH: HeaderName =
-    HeaderName::from_static("x-goog-upload-header-content-length");
-const X_GOOG_UPLOAD_HEADER_CONTENT_TYPE: HeaderName =
-    HeaderName::from_static("x-goog-upload-header-content-type");
-const X_GOOG_UPLOAD_OFFSET: HeaderName = HeaderName::from_static("x-goog-upload-offset");
-const X_GOOG_UPLOAD_PROTOCOL: HeaderName = HeaderName::from_static("x-goog-upload-protocol");
-const X_GOOG_UPLOAD_URL: HeaderName = HeaderName::from_static("x-goog-upload-url");
-
-const RESUMABLE: HeaderValue = HeaderValue::from_static("resumable");
-const START: HeaderValue = HeaderValue::from_static("start");
-const UPLOAD_FINALIZE: HeaderValue = HeaderValue::from_static("upload, finalize");
-const ZERO: HeaderValue = HeaderValue::from_static("0");
+const DEFAULT_API_VERSION: &str = "v1beta";
+const DEFAULT_MODEL: &str = "gemini-2.0-flash-exp";
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-004";
 
 #[derive(Clone, Debug, Default, Serialize)]
 pub struct GeminiRequest {
+    /// Overrides the client's model for this request
+    #[serde(skip)]
+    pub model: Option<String>,
+    /// Overrides the client's API version for this request
+    #[serde(skip)]
+    pub api_version: Option<String>,
     pub system_instruction: Option<GeminiSystemInstruction>,
     pub contents: Vec<GeminiMessage>,
     #[serde(rename = "safetySettings")]
     pub safety_settings: Vec<GeminiSafetySetting>,
     pub generation_config: Option<GeminiGenerationConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<GeminiTool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<GeminiToolConfig>,
+    /// Name of a cache holding the system instruction, tools and leading contents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_content: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GeminiGenerationConfig {
     #[serde(skip_serializing_if = "String::is_empty")]
     pub response_mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<GeminiSchema>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidate_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_config: Option<GeminiThinkingConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiThinkingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_thoughts: Option<bool>,
+    /// Tokens the model may spend thinking, `0` disables thinking where supported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<u32>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
@@ -68,25 +128,198 @@ pub struct GeminiSystemPart {
     pub text: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GeminiResponse {
+    #[serde(default)]
     pub candidates: Vec<GeminiCandidate>,
+    #[serde(default)]
     pub usage_metadata: GeminiUsageMetadata,
+    #[serde(default)]
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiPromptFeedback {
+    #[serde(default)]
+    pub block_reason: Option<String>,
+    #[serde(default)]
+    pub safety_ratings: Vec<GeminiSafetyRating>,
+}
+
+impl GeminiResponse {
+    /// Why the prompt was rejected, if nothing was generated because of it
+    pub fn block_reason(&self) -> Option<&str> {
+        if !self.candidates.is_empty() {
+            return None;
+        }
+
+        self.prompt_feedback.as_ref()?.block_reason.as_deref()
+    }
+
+    /// Parts of every candidate, in order
+    pub fn into_parts(self) -> Vec<Part> {
+        self.candidates
+            .into_iter()
+            .flat_map(|candidate| candidate.content)
+            .flat_map(|content| content.parts)
+            .collect()
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GeminiUsageMetadata {
+    #[serde(default)]
     pub prompt_token_count: u32,
     #[serde(default)]
+    pub cached_content_token_count: u32,
+    #[serde(default)]
     pub candidates_token_count: u32,
+    #[serde(default)]
+    pub thoughts_token_count: u32,
+    #[serde(default)]
     pub total_token_count: u32,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct GeminiCandidate {
-    pub content: GeminiMessage,
+    /// Missing when the candidate was blocked before producing anything
+    #[serde(default)]
+    pub content: Option<GeminiMessage>,
+    #[serde(default)]
+    pub finish_reason: Option<GeminiFinishReason>,
+    #[serde(default)]
+    pub safety_ratings: Vec<GeminiSafetyRating>,
+    #[serde(default)]
+    pub citation_metadata: Option<GeminiCitationMetadata>,
+}
+
+/// Why the model stopped generating
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiFinishReason {
+    /// Natural stop point or a stop sequence
+    Stop,
+    MaxTokens,
+    Safety,
+    Recitation,
+    Language,
+    Blocklist,
+    ProhibitedContent,
+    Spii,
+    MalformedFunctionCall,
+    ImageSafety,
+    Other,
+    #[serde(other)]
+    FinishReasonUnspecified,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiHarmCategory {
+    HarmCategoryHarassment,
+    HarmCategoryHateSpeech,
+    HarmCategorySexuallyExplicit,
+    HarmCategoryDangerousContent,
+    HarmCategoryCivicIntegrity,
+    #[serde(other)]
+    HarmCategoryUnspecified,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiHarmProbability {
+    Negligible,
+    Low,
+    Medium,
+    High,
+    #[serde(other)]
+    HarmProbabilityUnspecified,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiSafetyRating {
+    pub category: GeminiHarmCategory,
+    pub probability: GeminiHarmProbability,
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCitationMetadata {
+    #[serde(default)]
+    pub citation_sources: Vec<GeminiCitationSource>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCitationSource {
+    #[serde(default)]
+    pub start_index: Option<u32>,
+    #[serde(default)]
+    pub end_index: Option<u32>,
+    #[serde(default)]
+    pub uri: Option<String>,
+    #[serde(default)]
+    pub license: Option<String>,
+}
+
+/// Result of [`GeminiClient::generate`]: the generated parts and why generation ended
+#[derive(Clone, Debug, Default)]
+pub struct GenerateResponse {
+    /// Parts of every candidate, in order
+    pub parts: Vec<Part>,
+    /// Finish reason of the first candidate
+    pub finish_reason: Option<GeminiFinishReason>,
+    /// Safety ratings of the first candidate
+    pub safety_ratings: Vec<GeminiSafetyRating>,
+    /// Citations of the first candidate
+    pub citation_metadata: Option<GeminiCitationMetadata>,
+    pub prompt_feedback: Option<GeminiPromptFeedback>,
+    pub usage_metadata: GeminiUsageMetadata,
+}
+
+impl GenerateResponse {
+    /// Generated text without thoughts
+    pub fn text(&self) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(TextPart {
+                    text,
+                    thought: false,
+                }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl From<GeminiResponse> for GenerateResponse {
+    fn from(response: GeminiResponse) -> Self {
+        let (finish_reason, safety_ratings, citation_metadata) = match response.candidates.first() {
+            Some(candidate) => (
+                candidate.finish_reason,
+                candidate.safety_ratings.clone(),
+                candidate.citation_metadata.clone(),
+            ),
+            None => Default::default(),
+        };
+
+        Self {
+            finish_reason,
+            safety_ratings,
+            citation_metadata,
+            prompt_feedback: response.prompt_feedback.clone(),
+            usage_metadata: response.usage_metadata,
+            parts: response.into_parts(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -108,30 +341,16 @@ pub enum GeminiRole {
     Model,
 }
 
-#[derive(Serialize)]
-struct GeminiCreateFile<'a> {
-    file: GeminiFile<'a>,
-}
-
-#[derive(Serialize)]
-struct GeminiFile<'a> {
-    display_name: &'a str,
-}
-
-#[derive(Debug, Deserialize)]
-struct GeminiFileResponse {
-    file: GeminiFileUri,
-}
-
-#[derive(Debug, Deserialize)]
-struct GeminiFileUri {
-    uri: String,
-    state: String,
-}
-
 pub struct GeminiClient {
     api_key: String,
     base_url: String,
+    api_version: String,
+    model: String,
+    embedding_model: String,
+    retry_policy: RetryPolicy,
+    limiter: RateLimiter,
+    upload_chunk_size: usize,
+    poll_policy: PollPolicy,
     client: Client,
 }
 
@@ -148,125 +367,280 @@ impl GeminiClient {
         Self {
             api_key,
             base_url,
+            api_version: DEFAULT_API_VERSION.into(),
+            model: DEFAULT_MODEL.into(),
+            embedding_model: DEFAULT_EMBEDDING_MODEL.into(),
+            retry_policy: RetryPolicy::default(),
+            limiter: RateLimiter::new(RateLimits::default()),
+            upload_chunk_size: DEFAULT_UPLOAD_CHUNK_SIZE,
+            poll_policy: PollPolicy::default(),
             client,
         }
     }
 
+    /// Queue generation requests so they stay within `limits`
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limiter = RateLimiter::new(limits);
+        self
+    }
+
+    /// Current queue depth and in-flight generation requests
+    pub fn rate_limiter_metrics(&self) -> RateLimiterMetrics {
+        self.limiter.metrics()
+    }
+
+    /// Retry failed `generate` and Files API requests according to `policy`
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Upload files in chunks of `size` bytes, rounded up to a multiple of 256 KiB
+    pub fn with_upload_chunk_size(mut self, size: usize) -> Self {
+        self.upload_chunk_size =
+            size.div_ceil(UPLOAD_CHUNK_GRANULARITY).max(1) * UPLOAD_CHUNK_GRANULARITY;
+        self
+    }
+
+    /// Wait for uploaded files to finish processing according to `policy`
+    pub fn with_poll_policy(mut self, policy: PollPolicy) -> Self {
+        self.poll_policy = policy;
+        self
+    }
+
+    /// Use `model` (e.g. `gemini-1.5-pro` or `models/gemini-1.5-pro`) unless a request overrides it
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Embed with `model` (e.g. `text-embedding-004`)
+    pub fn with_embedding_model(mut self, model: impl Into<String>) -> Self {
+        self.embedding_model = model.into();
+        self
+    }
+
+    /// Use `api_version` (e.g. `v1`) unless a request overrides it
+    pub fn with_api_version(mut self, api_version: impl Into<String>) -> Self {
+        self.api_version = api_version.into();
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn api_version(&self) -> &str {
+        &self.api_version
+    }
+
     fn with_base(&self, path: &str) -> String {
         format!("{}/{path}", self.base_url)
     }
 
-    pub async fn create_file(
-        &self,
-        file_name: &str,
-        content_length: u32,
-        content_type: &str,
-    ) -> anyhow::Result<String> {
-        let url = self.with_base("upload/v1beta/files");
+    /// URL of a model method, honouring per-request overrides
+    fn model_url(&self, request: &GeminiRequest, method: &str) -> String {
+        let api_version = request.api_version.as_deref().unwrap_or(&self.api_version);
+
+        self.with_base(&format!(
+            "{api_version}/models/{}:{method}",
+            self.model_id(request)
+        ))
+    }
+
+    /// Model used for `request`, without the `models/` prefix
+    fn model_id<'a>(&'a self, request: &'a GeminiRequest) -> &'a str {
+        let model = request.model.as_deref().unwrap_or(&self.model);
+
+        model.strip_prefix("models/").unwrap_or(model)
+    }
+
+    pub async fn generate(&self, request: GeminiRequest) -> Result<GenerateResponse> {
+        let url = self.model_url(&request, "generateContent");
         let query = [("key", &self.api_key)];
-        let request = GeminiCreateFile {
-            file: GeminiFile {
-                display_name: file_name,
-            },
-        };
+        let estimate = estimate_request_tokens(&request);
 
         let response = self
-            .client
-            .post(url)
-            .query(&query)
-            .header(X_GOOG_UPLOAD_PROTOCOL, RESUMABLE)
-            .header(X_GOOG_UPLOAD_COMMAND, START)
-            .header(X_GOOG_UPLOAD_HEADER_CONTENT_LENGTH, content_length)
-            .header(X_GOOG_UPLOAD_HEADER_CONTENT_TYPE, content_type)
-            .json(&request)
-            .send()
+            .retry_policy
+            .run("generate", || async {
+                let permit = self.limiter.acquire(estimate).await;
+
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .json(&request)
+                    .send()
+                    .await?;
+
+                let response = decode::<GeminiResponse>(response).await?;
+
+                permit
+                    .settle(response.usage_metadata.total_token_count)
+                    .await;
+
+                Ok(response)
+            })
             .await?;
 
-        let url = response
-            .headers()
-            .get(X_GOOG_UPLOAD_URL)
-            .and_then(|value| value.to_str().map(String::from).ok())
-            .ok_or_else(|| anyhow::anyhow!("missing expected x-goog-upload-url"))?;
+        if let Some(reason) = response.block_reason() {
+            return Err(GeminiError::Blocked {
+                reason: reason.into(),
+            });
+        }
 
-        Ok(url)
+        Ok(response.into())
     }
 
-    pub async fn upload_file(
+    /// Like [`generate`](Self::generate), but yields partial responses as they are produced
+    ///
+    /// Each item holds the text generated since the previous one; concatenate
+    /// the parts to get the whole answer.
+    pub async fn generate_stream(
         &self,
-        url: String,
-        content_length: u32,
-        bytes: Vec<u8>,
-    ) -> anyhow::Result<String> {
-        let query = [("key", &self.api_key)];
-        let mut response = self
-            .client
-            .post(url)
-            .header(CONTENT_LENGTH, content_length)
-            .header(X_GOOG_UPLOAD_OFFSET, ZERO)
-            .header(X_GOOG_UPLOAD_COMMAND, UPLOAD_FINALIZE)
-            .body(bytes)
-            .send()
-            .await?
-            .json::<GeminiFileResponse>()
+        request: GeminiRequest,
+    ) -> Result<impl Stream<Item = Result<GeminiResponse>> + '_> {
+        let url = self.model_url(&request, "streamGenerateContent");
+        let query = [("alt", "sse"), ("key", &self.api_key)];
+        let estimate = estimate_request_tokens(&request);
+
+        let (response, permit) = self
+            .retry_policy
+            .run("generate stream", || async {
+                let permit = self.limiter.acquire(estimate).await;
+
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .json(&request)
+                    .send()
+                    .await?;
+
+                Ok((error_for_status(response).await?, permit))
+            })
             .await?;
 
-        tracing::debug!("initial upload file response: {response:#?}");
+        let stream = futures_util::stream::try_unfold(
+            (response, EventParser::default(), Some(permit), 0),
+            |(mut response, mut parser, mut permit, mut tokens)| async move {
+                loop {
+                    if let Some(data) = parser.next_event() {
+                        tracing::debug!("generate stream event: {data}");
 
-        while response.file.state == "PROCESSING" {
-            tokio::time::sleep(Duration::from_secs(5)).await;
+                        let chunk = serde_json::from_str::<GeminiResponse>(&data)
+                            .map_err(|error| GeminiError::decode(error, data.as_bytes()))?;
 
-            response.file = self
-                .client
-                .get(response.file.uri)
-                .query(&query)
-                .send()
-                .await
-                .inspect_err(|error| tracing::error!("processing file: {error}"))?
-                .json()
-                .await
-                .inspect_err(|error| tracing::error!("processing file: {error}"))?;
+                        tokens = tokens.max(chunk.usage_metadata.total_token_count);
 
-            tracing::debug!("processing file response: {response:#?}");
-        }
+                        return Ok(Some((chunk, (response, parser, permit, tokens))));
+                    }
+
+                    if parser.is_finished() {
+                        if let Some(permit) = permit.take() {
+                            permit.settle(tokens).await;
+                        }
+
+                        return Ok(None);
+                    }
+
+                    match response.chunk().await? {
+                        Some(bytes) => parser.feed(&bytes),
+                        None => parser.finish(),
+                    }
+                }
+            },
+        );
 
-        Ok(response.file.uri)
+        Ok(stream)
     }
 
-    pub async fn generate(&self, request: GeminiRequest) -> anyhow::Result<Vec<Part>> {
-        let url = self.with_base("v1beta/models/gemini-2.0-flash-exp:generateContent");
+    /// Count the tokens `request` would use, including system instruction and tools
+    ///
+    /// Queued behind the rate limiter like any other request, without using up tokens.
+    pub async fn count_tokens(&self, request: &GeminiRequest) -> Result<GeminiTokenCount> {
+        let url = self.model_url(request, "countTokens");
         let query = [("key", &self.api_key)];
+        let body = GeminiCountTokensRequest {
+            generate_content_request: GeminiCountTokensContent {
+                model: format!("models/{}", self.model_id(request)),
+                request,
+            },
+        };
 
-        let response = self
-            .client
-            .post(url)
-            .query(&query)
-            .json(&request)
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?;
+        self.retry_policy
+            .run("count tokens", || async {
+                let _permit = self.limiter.acquire(0).await;
 
-        tracing::debug!(
-            "generate response: {}",
-            serde_json::to_string_pretty(&response).unwrap()
-        );
+                let response = self
+                    .client
+                    .post(&url)
+                    .query(&query)
+                    .json(&body)
+                    .send()
+                    .await?;
 
-        let result =
-            serde_json::from_str::<GeminiResponse>(&serde_json::to_string(&response).unwrap());
+                decode(response).await
+            })
+            .await
+    }
+}
 
-        let response = match result {
-            Ok(response) => response,
-            Err(_error) => return Err(anyhow::anyhow!("{response}")),
-        };
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiCountTokensRequest<'a> {
+    generate_content_request: GeminiCountTokensContent<'a>,
+}
 
-        let parts = response
-            .candidates
-            .into_iter()
-            .flat_map(|candidate| candidate.content.parts)
-            .collect::<Vec<_>>();
+#[derive(Serialize)]
+struct GeminiCountTokensContent<'a> {
+    model: String,
+    #[serde(flatten)]
+    request: &'a GeminiRequest,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTokenCount {
+    #[serde(default)]
+    pub total_tokens: u32,
+    #[serde(default)]
+    pub cached_content_token_count: u32,
+}
+
+fn estimate_request_tokens(request: &GeminiRequest) -> u32 {
+    serde_json::to_vec(request)
+        .map(|body| estimate_tokens(&body))
+        .unwrap_or_default()
+}
+
+/// Turn a non-success response into [`GeminiError::Status`]
+async fn error_for_status(response: Response) -> Result<Response> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
 
-        Ok(parts)
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+
+    Err(GeminiError::from_status(status, &headers, &body))
+}
+
+/// Check the status and deserialize the JSON body
+async fn decode<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+
+    tracing::debug!("response {status}: {}", String::from_utf8_lossy(&body));
+
+    if !status.is_success() {
+        return Err(GeminiError::from_status(status, &headers, &body));
     }
+
+    serde_json::from_slice(&body).map_err(|error| GeminiError::decode(error, &body))
 }
 
 pub fn is_supported_mime(mime: &Mime) -> bool {
@@ -313,3 +687,449 @@ pub fn is_supported_mime(mime: &Mime) -> bool {
             | ("video", "3gpp")
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{client, retrying_client, MockResponse, MockServer};
+    use core::time::Duration;
+    use futures_util::TryStreamExt;
+    use reqwest::StatusCode;
+
+    fn text_response(text: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            serde_json::json!({"candidates": [{"content": {"role": "model", "parts": [{"text": text}]}}]}),
+        )
+    }
+
+    fn request() -> GeminiRequest {
+        GeminiRequest {
+            contents: vec![GeminiMessage::new(GeminiRole::User, vec!["hi".into()])],
+            ..Default::default()
+        }
+    }
+
+    fn text(parts: &[Part]) -> String {
+        parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.text.as_str(),
+                Part::TaggedPart(_) => "",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn generation_config_skips_unset() {
+        let config = GeminiGenerationConfig::default();
+
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::json!({})
+        );
+
+        let config = GeminiGenerationConfig {
+            response_mime_type: "application/json".into(),
+            response_schema: Some(GeminiSchema::new(GeminiSchemaType::String)),
+            stop_sequences: vec!["end".into()],
+            max_output_tokens: Some(256),
+            temperature: Some(0.5),
+            top_k: Some(40),
+            thinking_config: Some(GeminiThinkingConfig {
+                thinking_budget: Some(0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::json!({
+                "responseMimeType": "application/json",
+                "responseSchema": {"type": "STRING"},
+                "stopSequences": ["end"],
+                "maxOutputTokens": 256,
+                "temperature": 0.5,
+                "topK": 40,
+                "thinkingConfig": {"thinkingBudget": 0},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn generate_stream_yields_chunks() {
+        let event = |text: &str| {
+            let chunk = serde_json::json!({
+                "candidates": [{"content": {"role": "model", "parts": [{"text": text}]}}],
+                "usageMetadata": {"promptTokenCount": 1, "totalTokenCount": 2},
+            });
+
+            format!("data: {chunk}\r\n\r\n")
+        };
+
+        let first = event("hello ");
+        let (head, tail) = first.split_at(first.len() / 2);
+
+        let server = MockServer::start([MockResponse::new(200)
+            .header("content-type", "text/event-stream")
+            .body(head)
+            .body(tail)
+            // the last event isn't terminated by a blank line
+            .body(event("world").trim_end())
+            .chunk_delay(Duration::from_millis(10))])
+        .await;
+
+        let chunks = client(&server)
+            .generate_stream(request())
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(text(&chunks[0].clone().into_parts()), "hello ");
+        assert_eq!(text(&chunks[1].clone().into_parts()), "world");
+
+        let requests = server.requests();
+
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].header("content-type"), Some("application/json"));
+        assert_eq!(
+            requests[0].target,
+            "/v1beta/models/gemini-2.0-flash-exp:streamGenerateContent?alt=sse&key=key"
+        );
+        assert_eq!(requests[0].json()["contents"][0]["parts"][0]["text"], "hi");
+    }
+
+    #[tokio::test]
+    async fn generate_stream_error_status() {
+        let server = MockServer::start([MockResponse::json(
+            400,
+            serde_json::json!({"error": {"code": 400, "message": "bad", "status": "INVALID_ARGUMENT"}}),
+        )])
+        .await;
+
+        let Err(error) = client(&server).generate_stream(request()).await else {
+            panic!("expected error");
+        };
+
+        assert_eq!(error.status(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(error.api_error().unwrap().status, "INVALID_ARGUMENT");
+    }
+
+    #[tokio::test]
+    async fn generate_model_override() {
+        let response = || {
+            MockResponse::json(
+                200,
+                serde_json::json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "ok"}]}}]}),
+            )
+        };
+
+        let server = MockServer::start([response(), response(), response()]).await;
+        let client = client(&server).with_model("models/gemini-1.5-pro");
+
+        client.generate(request()).await.unwrap();
+
+        client
+            .generate(GeminiRequest {
+                model: Some("gemini-1.5-flash".into()),
+                ..request()
+            })
+            .await
+            .unwrap();
+
+        client
+            .generate(GeminiRequest {
+                api_version: Some("v1".into()),
+                ..request()
+            })
+            .await
+            .unwrap();
+
+        let targets = server
+            .requests()
+            .into_iter()
+            .map(|request| request.target)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            targets,
+            [
+                "/v1beta/models/gemini-1.5-pro:generateContent?key=key",
+                "/v1beta/models/gemini-1.5-flash:generateContent?key=key",
+                "/v1/models/gemini-1.5-pro:generateContent?key=key",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn count_tokens() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            serde_json::json!({"totalTokens": 31}),
+        )])
+        .await;
+
+        let count = client(&server)
+            .count_tokens(&GeminiRequest {
+                model: Some("models/gemini-1.5-pro".into()),
+                ..request()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(count.total_tokens, 31);
+
+        let requests = server.requests();
+        let body = requests[0].json();
+
+        assert_eq!(
+            requests[0].target,
+            "/v1beta/models/gemini-1.5-pro:countTokens?key=key"
+        );
+        assert_eq!(
+            body["generateContentRequest"]["model"],
+            "models/gemini-1.5-pro"
+        );
+        assert_eq!(
+            body["generateContentRequest"]["contents"][0]["parts"][0]["text"],
+            "hi"
+        );
+    }
+
+    #[tokio::test]
+    async fn count_tokens_waits_for_rate_limit() {
+        let server = MockServer::start([text_response("a")]).await;
+        let client = client(&server).with_rate_limits(RateLimits {
+            requests_per_minute: Some(1),
+            ..Default::default()
+        });
+
+        client.generate(request()).await.unwrap();
+
+        let waited =
+            tokio::time::timeout(Duration::from_millis(100), client.count_tokens(&request())).await;
+
+        assert!(waited.is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_quota_error() {
+        let server = MockServer::start([MockResponse::json(
+            429,
+            serde_json::json!({"error": {
+                "code": 429,
+                "message": "quota",
+                "status": "RESOURCE_EXHAUSTED",
+                "details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "3s"}],
+            }}),
+        )])
+        .await;
+
+        let error = client(&server).generate(request()).await.unwrap_err();
+
+        let GeminiError::Status { status, error, .. } = error else {
+            panic!("unexpected error: {error:?}");
+        };
+
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.status, "RESOURCE_EXHAUSTED");
+        assert_eq!(error.message, "quota");
+        assert_eq!(error.details.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_retries_transient_errors() {
+        let server = MockServer::start([
+            MockResponse::new(503).body("unavailable"),
+            MockResponse::new(500).header("retry-after", "0"),
+            text_response("ok"),
+        ])
+        .await;
+
+        let response = retrying_client(&server).generate(request()).await.unwrap();
+
+        assert_eq!(response.text(), "ok");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn generate_follows_retry_info() {
+        let server = MockServer::start([
+            MockResponse::json(
+                429,
+                serde_json::json!({"error": {
+                    "code": 429,
+                    "status": "RESOURCE_EXHAUSTED",
+                    "details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "0.2s"}],
+                }}),
+            ),
+            text_response("ok"),
+        ])
+        .await;
+
+        let start = tokio::time::Instant::now();
+
+        retrying_client(&server).generate(request()).await.unwrap();
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn generate_gives_up_after_max_attempts() {
+        let server = MockServer::start([
+            MockResponse::new(503),
+            MockResponse::new(503),
+            MockResponse::new(503),
+            text_response("too late"),
+        ])
+        .await;
+
+        let error = retrying_client(&server)
+            .generate(request())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn generate_does_not_retry_client_errors() {
+        let server = MockServer::start([MockResponse::new(400), text_response("ok")]).await;
+
+        let error = retrying_client(&server)
+            .generate(request())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.status(), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_waits_for_rate_limit() {
+        let server = MockServer::start([text_response("a"), text_response("b")]).await;
+        let client = client(&server).with_rate_limits(RateLimits {
+            requests_per_minute: Some(1),
+            ..Default::default()
+        });
+
+        client.generate(request()).await.unwrap();
+
+        let pending = client.generate(request());
+
+        tokio::pin!(pending);
+
+        let waited = tokio::time::timeout(Duration::from_millis(100), &mut pending).await;
+
+        assert!(waited.is_err());
+        assert_eq!(client.rate_limiter_metrics().queued, 1);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn generate_non_json_error() {
+        let server =
+            MockServer::start([MockResponse::new(502).body("upstream connect error")]).await;
+
+        let error = client(&server).generate(request()).await.unwrap_err();
+
+        assert_eq!(error.status(), Some(StatusCode::BAD_GATEWAY));
+        assert_eq!(error.api_error().unwrap().message, "upstream connect error");
+    }
+
+    #[tokio::test]
+    async fn generate_finish_metadata() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            serde_json::json!({
+                "candidates": [{
+                    "content": {"role": "model", "parts": [{"text": "partial", "thought": true}, {"text": "answer"}]},
+                    "finishReason": "MAX_TOKENS",
+                    "safetyRatings": [
+                        {"category": "HARM_CATEGORY_HARASSMENT", "probability": "LOW"},
+                        {"category": "HARM_CATEGORY_SOMETHING_NEW", "probability": "NEGLIGIBLE"},
+                    ],
+                    "citationMetadata": {"citationSources": [{"startIndex": 0, "endIndex": 6, "uri": "https://example.com"}]},
+                }],
+                "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15},
+                "modelVersion": "gemini-2.0-flash-exp",
+            }),
+        )])
+        .await;
+
+        let response = client(&server).generate(request()).await.unwrap();
+
+        assert_eq!(response.text(), "answer");
+        assert_eq!(response.finish_reason, Some(GeminiFinishReason::MaxTokens));
+        assert_eq!(
+            response.safety_ratings,
+            [
+                GeminiSafetyRating {
+                    category: GeminiHarmCategory::HarmCategoryHarassment,
+                    probability: GeminiHarmProbability::Low,
+                    blocked: false,
+                },
+                GeminiSafetyRating {
+                    category: GeminiHarmCategory::HarmCategoryUnspecified,
+                    probability: GeminiHarmProbability::Negligible,
+                    blocked: false,
+                },
+            ]
+        );
+        assert_eq!(
+            response.citation_metadata.unwrap().citation_sources[0]
+                .uri
+                .as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(response.usage_metadata.total_token_count, 15);
+    }
+
+    #[tokio::test]
+    async fn generate_candidate_without_content() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            serde_json::json!({"candidates": [{"finishReason": "SAFETY"}]}),
+        )])
+        .await;
+
+        let response = client(&server).generate(request()).await.unwrap();
+
+        assert!(response.parts.is_empty());
+        assert_eq!(response.finish_reason, Some(GeminiFinishReason::Safety));
+    }
+
+    #[tokio::test]
+    async fn generate_blocked() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            serde_json::json!({"promptFeedback": {"blockReason": "SAFETY"}}),
+        )])
+        .await;
+
+        let error = client(&server).generate(request()).await.unwrap_err();
+
+        assert!(matches!(error, GeminiError::Blocked { reason } if reason == "SAFETY"));
+    }
+
+    #[tokio::test]
+    async fn generate_decode_error() {
+        let server = MockServer::start([MockResponse::json(
+            200,
+            serde_json::json!({"candidates": "nope"}),
+        )])
+        .await;
+
+        let error = client(&server).generate(request()).await.unwrap_err();
+
+        assert!(matches!(error, GeminiError::Decode { .. }));
+    }
+}
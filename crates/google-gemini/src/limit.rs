@@ -0,0 +1,272 @@
+//! Client-side request and token budgets, so bursts queue up instead of hitting quota errors.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// Limits applied to generation requests, `None` means unlimited
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+/// Snapshot of the limiter's queue
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RateLimiterMetrics {
+    /// Requests waiting for a budget or a free slot
+    pub queued: usize,
+    /// Requests currently being sent
+    pub in_flight: usize,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(limit: u32) -> Self {
+        let capacity = f64::from(limit.max(1));
+
+        Self {
+            capacity,
+            available: capacity,
+            per_second: capacity / 60.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.available = (self.available + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Wait until `cost` is available and take it
+    async fn take(&mut self, cost: f64) {
+        let cost = cost.min(self.capacity);
+
+        loop {
+            self.refill();
+
+            if self.available >= cost {
+                self.available -= cost;
+
+                return;
+            }
+
+            let missing = cost - self.available;
+
+            tokio::time::sleep(Duration::from_secs_f64(missing / self.per_second)).await;
+        }
+    }
+
+    /// Charge (or refund) the difference between an estimate and the actual cost
+    fn adjust(&mut self, difference: f64) {
+        self.refill();
+        self.available = (self.available - difference).min(self.capacity);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    requests: Option<Mutex<TokenBucket>>,
+    tokens: Option<Mutex<TokenBucket>>,
+    slots: Option<Semaphore>,
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            requests: limits
+                .requests_per_minute
+                .map(|limit| Mutex::new(TokenBucket::per_minute(limit))),
+            tokens: limits
+                .tokens_per_minute
+                .map(|limit| Mutex::new(TokenBucket::per_minute(limit))),
+            slots: limits
+                .max_concurrent_requests
+                .map(|limit| Semaphore::new(limit.max(1))),
+            queued: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn metrics(&self) -> RateLimiterMetrics {
+        RateLimiterMetrics {
+            queued: self.queued.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Wait for a free slot and budget for one request of about `tokens` tokens
+    ///
+    /// Waiters are served in arrival order.
+    pub async fn acquire(&self, tokens: u32) -> RateLimitPermit<'_> {
+        let queued = Counter::new(&self.queued);
+
+        tracing::trace!("rate limiter: {:?}", self.metrics());
+
+        let slot = match &self.slots {
+            Some(slots) => Some(slots.acquire().await.expect("semaphore is never closed")),
+            None => None,
+        };
+
+        if let Some(requests) = &self.requests {
+            requests.lock().await.take(1.0).await;
+        }
+
+        if let Some(bucket) = &self.tokens {
+            bucket.lock().await.take(f64::from(tokens)).await;
+        }
+
+        drop(queued);
+
+        RateLimitPermit {
+            _slot: slot,
+            _in_flight: Counter::new(&self.in_flight),
+            limiter: self,
+            estimate: tokens,
+        }
+    }
+}
+
+/// Held while a request is in flight
+pub(crate) struct RateLimitPermit<'a> {
+    _slot: Option<SemaphorePermit<'a>>,
+    _in_flight: Counter<'a>,
+    limiter: &'a RateLimiter,
+    estimate: u32,
+}
+
+impl RateLimitPermit<'_> {
+    /// Correct the token budget once the real usage is known
+    pub async fn settle(self, tokens: u32) {
+        if let Some(bucket) = &self.limiter.tokens {
+            bucket
+                .lock()
+                .await
+                .adjust(f64::from(tokens) - f64::from(self.estimate));
+        }
+    }
+}
+
+/// Increments a counter for as long as it lives, even if the future holding it is dropped
+struct Counter<'a>(&'a AtomicUsize);
+
+impl<'a> Counter<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Self(counter)
+    }
+}
+
+impl Drop for Counter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Rough token count for a request body, about four bytes per token
+pub fn estimate_tokens(body: &[u8]) -> u32 {
+    u32::try_from(body.len().div_ceil(4)).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_per_minute_queue() {
+        let limiter = RateLimiter::new(RateLimits {
+            requests_per_minute: Some(2),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+
+        drop(limiter.acquire(0).await);
+        drop(limiter.acquire(0).await);
+
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        drop(limiter.acquire(0).await);
+
+        assert!(start.elapsed() >= Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_per_minute_settle() {
+        let limiter = RateLimiter::new(RateLimits {
+            tokens_per_minute: Some(600),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+
+        // estimated 100 but actually used 600, so the next request has to wait for a refill
+        limiter.acquire(100).await.settle(600).await;
+        drop(limiter.acquire(60).await);
+
+        assert!(start.elapsed() >= Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrency_cap_and_metrics() {
+        let limiter = Arc::new(RateLimiter::new(RateLimits {
+            max_concurrent_requests: Some(1),
+            ..Default::default()
+        }));
+
+        let permit = limiter.acquire(0).await;
+
+        assert_eq!(
+            limiter.metrics(),
+            RateLimiterMetrics {
+                queued: 0,
+                in_flight: 1
+            }
+        );
+
+        let waiter = tokio::spawn({
+            let limiter = Arc::clone(&limiter);
+
+            async move {
+                drop(limiter.acquire(0).await);
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(
+            limiter.metrics(),
+            RateLimiterMetrics {
+                queued: 1,
+                in_flight: 1
+            }
+        );
+
+        drop(permit);
+        waiter.await.unwrap();
+
+        assert_eq!(limiter.metrics(), RateLimiterMetrics::default());
+    }
+
+    #[test]
+    fn estimates() {
+        assert_eq!(estimate_tokens(b""), 0);
+        assert_eq!(estimate_tokens(b"hello"), 2);
+    }
+}
@@ -0,0 +1,211 @@
+//! Minimal HTTP/1.1 server standing in for the Gemini API in tests.
+
+use crate::{GeminiClient, RetryPolicy};
+use alloc::sync::Arc;
+use core::time::Duration;
+use reqwest::Client;
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Client talking to `server`, without retries
+pub(crate) fn client(server: &MockServer) -> GeminiClient {
+    GeminiClient::new_with_base_url_and_client("key".into(), server.base_url(), Client::new())
+        .with_retry_policy(RetryPolicy::none())
+}
+
+/// Client talking to `server`, retrying right away
+pub(crate) fn retrying_client(server: &MockServer) -> GeminiClient {
+    client(server).with_retry_policy(RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        jitter: 0.0,
+        ..Default::default()
+    })
+}
+
+/// Canned response, served once
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub chunks: Vec<Vec<u8>>,
+    pub chunk_delay: Duration,
+}
+
+impl MockResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            chunks: Vec::new(),
+            chunk_delay: Duration::ZERO,
+        }
+    }
+
+    pub fn json(status: u16, value: serde_json::Value) -> Self {
+        Self::new(status)
+            .header("content-type", "application/json")
+            .body(value.to_string())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.chunks.push(body.into());
+        self
+    }
+
+    pub fn chunk_delay(mut self, delay: Duration) -> Self {
+        self.chunk_delay = delay;
+        self
+    }
+}
+
+/// Request as received by the server
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _value)| key.eq_ignore_ascii_case(name))
+            .map(|(_key, value)| value.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+pub struct MockServer {
+    base_url: String,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl MockServer {
+    /// Serve `responses` in order, one per connection
+    ///
+    /// Once exhausted every further request gets a 500.
+    pub async fn start(responses: impl IntoIterator<Item = MockResponse>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::<Mutex<Vec<RecordedRequest>>>::default();
+        let mut responses = responses.into_iter().collect::<Vec<_>>().into_iter();
+
+        tokio::spawn({
+            let requests = Arc::clone(&requests);
+
+            async move {
+                while let Ok((mut stream, _addr)) = listener.accept().await {
+                    let Some(request) = read_request(&mut stream).await else {
+                        continue;
+                    };
+
+                    requests.lock().unwrap().push(request);
+
+                    let response = responses.next().unwrap_or_else(|| MockResponse::new(500));
+
+                    tokio::spawn(write_response(stream, response));
+                }
+            }
+        });
+
+        Self { base_url, requests }
+    }
+
+    pub fn base_url(&self) -> String {
+        self.base_url.clone()
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<RecordedRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+
+    let head_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+
+        let read = stream.read(&mut chunk).await.ok()?;
+
+        if read == 0 {
+            return None;
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).into_owned();
+    let mut body = buffer[head_end + 4..].to_vec();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect::<Vec<_>>();
+
+    let content_length = headers
+        .iter()
+        .find(|(key, _value)| key == "content-length")
+        .and_then(|(_key, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await.ok()?;
+
+        if read == 0 {
+            break;
+        }
+
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    Some(RecordedRequest {
+        method,
+        target,
+        headers,
+        body,
+    })
+}
+
+async fn write_response(mut stream: TcpStream, response: MockResponse) {
+    let mut head = format!("HTTP/1.1 {} Mock\r\nconnection: close\r\n", response.status);
+
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    head.push_str("\r\n");
+
+    if stream.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+
+    for chunk in response.chunks {
+        if stream.write_all(&chunk).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+
+        tokio::time::sleep(response.chunk_delay).await;
+    }
+
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,200 @@
+use crate::{GeminiError, Result};
+use core::future::Future;
+use core::time::Duration;
+use reqwest::StatusCode;
+
+/// When and how often failed requests are retried
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, `1` disables retrying
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub base_delay: Duration,
+    /// Upper bound for the computed backoff, server-requested delays are not capped
+    pub max_delay: Duration,
+    /// Fraction of the delay, between `0.0` and `1.0`, randomly added or subtracted
+    pub jitter: f64,
+    /// Statuses worth retrying
+    pub retry_statuses: Vec<StatusCode>,
+    /// Also retry connection failures and timeouts
+    pub retry_network_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            retry_statuses: vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            retry_network_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn is_retryable(&self, error: &GeminiError) -> bool {
+        match error {
+            GeminiError::Status { status, .. } => self.retry_statuses.contains(status),
+            GeminiError::Http(error) => {
+                self.retry_network_errors && (error.is_connect() || error.is_timeout())
+            }
+            _ => false,
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 1), `None` if `error` should not be retried
+    fn delay(&self, retry: u32, error: &GeminiError) -> Option<Duration> {
+        self.backoff(retry, error, self.is_retryable(error))
+    }
+
+    /// Like [`Self::delay`], but any network failure counts since uploads resume where they broke
+    pub(crate) fn upload_delay(&self, retry: u32, error: &GeminiError) -> Option<Duration> {
+        let retryable = match error {
+            GeminiError::Http(_) => self.retry_network_errors,
+            error => self.is_retryable(error),
+        };
+
+        self.backoff(retry, error, retryable)
+    }
+
+    fn backoff(&self, retry: u32, error: &GeminiError, retryable: bool) -> Option<Duration> {
+        if retry >= self.max_attempts || !retryable {
+            return None;
+        }
+
+        if let Some(delay) = error.retry_delay() {
+            return Some(delay);
+        }
+
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+
+        Some(backoff.mul_f64(1.0 + jitter))
+    }
+
+    /// Run `operation` until it succeeds, fails permanently or runs out of attempts
+    pub(crate) async fn run<T, F, Fut>(&self, name: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 1;
+
+        loop {
+            let error = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+
+            let Some(delay) = self.delay(retry, &error) else {
+                return Err(error);
+            };
+
+            tracing::warn!("{name} failed, retrying in {delay:?} (attempt {retry}): {error}");
+
+            tokio::time::sleep(delay).await;
+
+            retry += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GeminiApiError;
+
+    fn status(status: StatusCode) -> GeminiError {
+        GeminiError::Status {
+            status,
+            error: GeminiApiError {
+                code: status.as_u16(),
+                message: String::new(),
+                status: String::new(),
+                details: Vec::new(),
+            },
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            jitter: 0.0,
+            max_delay: Duration::from_secs(5),
+            ..Default::default()
+        };
+
+        let error = status(StatusCode::SERVICE_UNAVAILABLE);
+        let delays = (1..=5)
+            .map(|retry| policy.delay(retry, &error).unwrap().as_secs())
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let delay = policy
+                .delay(1, &status(StatusCode::TOO_MANY_REQUESTS))
+                .unwrap();
+
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1500));
+        }
+    }
+
+    #[test]
+    fn gives_up() {
+        let policy = RetryPolicy::default();
+
+        assert!(policy.delay(1, &status(StatusCode::BAD_REQUEST)).is_none());
+        assert!(policy
+            .delay(
+                policy.max_attempts,
+                &status(StatusCode::SERVICE_UNAVAILABLE)
+            )
+            .is_none());
+        assert!(RetryPolicy::none()
+            .delay(1, &status(StatusCode::SERVICE_UNAVAILABLE))
+            .is_none());
+    }
+
+    #[test]
+    fn server_delay_wins() {
+        let policy = RetryPolicy::default();
+        let mut error = status(StatusCode::TOO_MANY_REQUESTS);
+
+        if let GeminiError::Status { retry_after, .. } = &mut error {
+            *retry_after = Some(Duration::from_secs(42));
+        }
+
+        assert_eq!(policy.delay(1, &error), Some(Duration::from_secs(42)));
+    }
+}
@@ -0,0 +1,375 @@
+//! OpenAPI subset understood by `responseSchema` and function declarations.
+
+use alloc::collections::BTreeMap;
+use derive_more::{Display, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Deepest nesting accepted when inlining references, guards against recursive types
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Display, Error)]
+pub enum GeminiSchemaError {
+    #[display("unresolved reference {_0}")]
+    UnresolvedReference(#[error(not(source))] String),
+    #[display("schema nested deeper than {MAX_DEPTH} levels")]
+    TooDeep,
+    #[display("unsupported type {_0}")]
+    UnsupportedType(#[error(not(source))] String),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GeminiSchemaType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array,
+    Object,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiSchema {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub schema_type: Option<GeminiSchemaType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nullable: Option<bool>,
+    #[serde(rename = "enum", default, skip_serializing_if = "Vec::is_empty")]
+    pub enum_values: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_items: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, GeminiSchema>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub property_ordering: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<GeminiSchema>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub any_of: Vec<GeminiSchema>,
+}
+
+impl GeminiSchema {
+    pub fn new(schema_type: GeminiSchemaType) -> Self {
+        Self {
+            schema_type: Some(schema_type),
+            ..Default::default()
+        }
+    }
+}
+
+impl GeminiSchema {
+    /// Convert a JSON Schema document (e.g. from schemars) into the subset Gemini accepts
+    ///
+    /// `$ref`s into `definitions`/`$defs` are inlined, `oneOf` becomes `anyOf`,
+    /// `null` alternatives become `nullable` and unsupported keywords are dropped.
+    pub fn from_json_schema(root: &Value) -> Result<Self, GeminiSchemaError> {
+        Converter { root }.convert(root, 0)
+    }
+}
+
+struct Converter<'a> {
+    root: &'a Value,
+}
+
+impl Converter<'_> {
+    fn resolve(&self, reference: &str) -> Result<&Value, GeminiSchemaError> {
+        reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| GeminiSchemaError::UnresolvedReference(reference.into()))
+    }
+
+    fn convert(&self, schema: &Value, depth: usize) -> Result<GeminiSchema, GeminiSchemaError> {
+        if depth > MAX_DEPTH {
+            return Err(GeminiSchemaError::TooDeep);
+        }
+
+        let Some(object) = schema.as_object() else {
+            return Ok(GeminiSchema::default());
+        };
+
+        if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+            let mut resolved = self.convert(self.resolve(reference)?, depth + 1)?;
+
+            if let Some(description) = object.get("description").and_then(Value::as_str) {
+                resolved.description = Some(description.into());
+            }
+
+            return Ok(resolved);
+        }
+
+        if let Some([single]) = object
+            .get("allOf")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+        {
+            return self.convert(single, depth + 1);
+        }
+
+        let alternatives = object
+            .get("anyOf")
+            .or_else(|| object.get("oneOf"))
+            .and_then(Value::as_array);
+
+        if let Some(alternatives) = alternatives {
+            return self.convert_alternatives(object, alternatives, depth);
+        }
+
+        let mut result = GeminiSchema {
+            description: object
+                .get("description")
+                .and_then(Value::as_str)
+                .map(String::from),
+            ..Default::default()
+        };
+
+        match object.get("type") {
+            Some(Value::String(schema_type)) => {
+                result.schema_type = Some(schema_type_of(schema_type)?);
+            }
+            Some(Value::Array(types)) => {
+                let mut types = types.iter().filter_map(Value::as_str).collect::<Vec<_>>();
+
+                if let Some(position) = types.iter().position(|schema_type| *schema_type == "null")
+                {
+                    types.remove(position);
+                    result.nullable = Some(true);
+                }
+
+                if let [schema_type] = types.as_slice() {
+                    result.schema_type = Some(schema_type_of(schema_type)?);
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(Value::String(value)) = object.get("const") {
+            result.schema_type.get_or_insert(GeminiSchemaType::String);
+            result.enum_values.push(value.clone());
+        }
+
+        if let Some(values) = object.get("enum").and_then(Value::as_array) {
+            result.schema_type.get_or_insert(GeminiSchemaType::String);
+            result
+                .enum_values
+                .extend(values.iter().filter_map(Value::as_str).map(String::from));
+        }
+
+        result.format = object
+            .get("format")
+            .and_then(Value::as_str)
+            .filter(|format| is_supported_format(result.schema_type, format))
+            .map(String::from);
+
+        result.minimum = object.get("minimum").and_then(Value::as_f64);
+        result.maximum = object.get("maximum").and_then(Value::as_f64);
+        result.min_items = object.get("minItems").and_then(Value::as_u64);
+        result.max_items = object.get("maxItems").and_then(Value::as_u64);
+
+        if let Some(items) = object.get("items") {
+            result.items = Some(Box::new(self.convert(items, depth + 1)?));
+        }
+
+        if let Some(properties) = object.get("properties").and_then(Value::as_object) {
+            for (name, property) in properties {
+                result
+                    .properties
+                    .insert(name.clone(), self.convert(property, depth + 1)?);
+            }
+
+            result.schema_type.get_or_insert(GeminiSchemaType::Object);
+        }
+
+        if let Some(required) = object.get("required").and_then(Value::as_array) {
+            result
+                .required
+                .extend(required.iter().filter_map(Value::as_str).map(String::from));
+        }
+
+        Ok(result)
+    }
+
+    fn convert_alternatives(
+        &self,
+        object: &Map<String, Value>,
+        alternatives: &[Value],
+        depth: usize,
+    ) -> Result<GeminiSchema, GeminiSchemaError> {
+        let is_null = |schema: &Value| schema.get("type").and_then(Value::as_str) == Some("null");
+        let nullable = alternatives.iter().any(is_null);
+
+        let mut alternatives = alternatives
+            .iter()
+            .filter(|schema| !is_null(schema))
+            .map(|schema| self.convert(schema, depth + 1))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut result = if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            GeminiSchema {
+                any_of: alternatives,
+                ..Default::default()
+            }
+        };
+
+        if nullable {
+            result.nullable = Some(true);
+        }
+
+        if let Some(description) = object.get("description").and_then(Value::as_str) {
+            result.description = Some(description.into());
+        }
+
+        Ok(result)
+    }
+}
+
+fn schema_type_of(schema_type: &str) -> Result<GeminiSchemaType, GeminiSchemaError> {
+    Ok(match schema_type {
+        "string" => GeminiSchemaType::String,
+        "number" => GeminiSchemaType::Number,
+        "integer" => GeminiSchemaType::Integer,
+        "boolean" => GeminiSchemaType::Boolean,
+        "array" => GeminiSchemaType::Array,
+        "object" => GeminiSchemaType::Object,
+        other => return Err(GeminiSchemaError::UnsupportedType(other.into())),
+    })
+}
+
+fn is_supported_format(schema_type: Option<GeminiSchemaType>, format: &str) -> bool {
+    matches!(
+        (schema_type, format),
+        (Some(GeminiSchemaType::Integer), "int32" | "int64")
+            | (Some(GeminiSchemaType::Number), "float" | "double")
+            | (Some(GeminiSchemaType::String), "enum" | "date-time")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn inlines_references_and_variants() {
+        let root = json!({
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "type": "array",
+            "items": {"$ref": "#/definitions/Action"},
+            "definitions": {
+                "Action": {
+                    "oneOf": [
+                        {
+                            "type": "object",
+                            "required": ["Send"],
+                            "properties": {
+                                "Send": {
+                                    "type": "object",
+                                    "required": ["content"],
+                                    "properties": {
+                                        "content": {"type": "string"},
+                                        "reply_to": {
+                                            "default": null,
+                                            "anyOf": [{"$ref": "#/definitions/Id"}, {"type": "null"}],
+                                        },
+                                    },
+                                },
+                            },
+                            "additionalProperties": false,
+                        },
+                        {
+                            "type": "object",
+                            "required": ["Pin"],
+                            "properties": {"Pin": {"$ref": "#/definitions/Id"}},
+                        },
+                    ],
+                },
+                "Id": {"type": "integer", "format": "uint64", "minimum": 1.0},
+            },
+        });
+
+        let schema = GeminiSchema::from_json_schema(&root).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&schema).unwrap(),
+            json!({
+                "type": "ARRAY",
+                "items": {
+                    "anyOf": [
+                        {
+                            "type": "OBJECT",
+                            "required": ["Send"],
+                            "properties": {
+                                "Send": {
+                                    "type": "OBJECT",
+                                    "required": ["content"],
+                                    "properties": {
+                                        "content": {"type": "STRING"},
+                                        "reply_to": {"type": "INTEGER", "minimum": 1.0, "nullable": true},
+                                    },
+                                },
+                            },
+                        },
+                        {
+                            "type": "OBJECT",
+                            "required": ["Pin"],
+                            "properties": {"Pin": {"type": "INTEGER", "minimum": 1.0}},
+                        },
+                    ],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn type_arrays_and_enums() {
+        let schema = GeminiSchema::from_json_schema(&json!({
+            "type": ["string", "null"],
+            "enum": ["a", "b"],
+            "format": "date-time",
+        }))
+        .unwrap();
+
+        assert_eq!(schema.schema_type, Some(GeminiSchemaType::String));
+        assert_eq!(schema.nullable, Some(true));
+        assert_eq!(schema.enum_values, ["a", "b"]);
+        assert_eq!(schema.format.as_deref(), Some("date-time"));
+    }
+
+    #[test]
+    fn unresolved_reference() {
+        let result = GeminiSchema::from_json_schema(&json!({"$ref": "#/definitions/Missing"}));
+
+        assert!(matches!(
+            result,
+            Err(GeminiSchemaError::UnresolvedReference(_))
+        ));
+    }
+
+    #[test]
+    fn recursive_reference() {
+        let result = GeminiSchema::from_json_schema(&json!({
+            "$ref": "#/definitions/Node",
+            "definitions": {
+                "Node": {"type": "object", "properties": {"next": {"$ref": "#/definitions/Node"}}},
+            },
+        }));
+
+        assert!(matches!(result, Err(GeminiSchemaError::TooDeep)));
+    }
+}
@@ -0,0 +1,125 @@
+//! Incremental parser for `text/event-stream` bodies.
+
+/// Accumulates raw bytes and yields the `data` payload of each complete event
+#[derive(Debug, Default)]
+pub(crate) struct EventParser {
+    buffer: Vec<u8>,
+    data: String,
+    finished: bool,
+}
+
+impl EventParser {
+    /// Append a chunk of the response body
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    /// Mark the end of the body, so a last event without a trailing blank line is flushed
+    pub fn finish(&mut self) {
+        if !self.buffer.is_empty() {
+            self.buffer.push(b'\n');
+        }
+
+        self.buffer.push(b'\n');
+        self.finished = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Take the next fully received event, if any
+    ///
+    /// Comments, `event`, `id` and `retry` fields are ignored.
+    pub fn next_event(&mut self) -> Option<String> {
+        while let Some(position) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let mut line = self.buffer.drain(..=position).collect::<Vec<_>>();
+
+            line.pop();
+
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            let line = String::from_utf8_lossy(&line);
+
+            if line.is_empty() {
+                if self.data.is_empty() {
+                    continue;
+                }
+
+                return Some(core::mem::take(&mut self.data));
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+
+            if field == "data" {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+
+                self.data.push_str(value);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_event() {
+        let mut parser = EventParser::default();
+
+        parser.feed(b"data: {\"a\":1}\r\n\r\n");
+
+        assert_eq!(parser.next_event().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(parser.next_event(), None);
+    }
+
+    #[test]
+    fn split_across_chunks() {
+        let mut parser = EventParser::default();
+
+        parser.feed(b"data: {\"a\"");
+        assert_eq!(parser.next_event(), None);
+
+        parser.feed(b":1}\n");
+        assert_eq!(parser.next_event(), None);
+
+        parser.feed(b"\ndata: 2\n\n");
+        assert_eq!(parser.next_event().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(parser.next_event().as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn multiline_data_and_comments() {
+        let mut parser = EventParser::default();
+
+        parser.feed(b": keep-alive\n\nevent: message\ndata: a\ndata:b\n\n");
+
+        assert_eq!(parser.next_event().as_deref(), Some("a\nb"));
+    }
+
+    #[test]
+    fn flushes_at_end_of_body() {
+        let mut parser = EventParser::default();
+
+        parser.feed(b"data: 1\n\ndata: 2");
+        assert_eq!(parser.next_event().as_deref(), Some("1"));
+        assert_eq!(parser.next_event(), None);
+
+        parser.finish();
+        assert_eq!(parser.next_event().as_deref(), Some("2"));
+        assert_eq!(parser.next_event(), None);
+
+        let mut empty = EventParser::default();
+
+        empty.finish();
+        assert_eq!(empty.next_event(), None);
+    }
+}
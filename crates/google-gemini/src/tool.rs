@@ -0,0 +1,317 @@
+//! Function calling: tool declarations and the generate/dispatch loop.
+
+use crate::content::{FunctionCallPart, FunctionResponsePart, TaggedPart};
+use crate::{
+    GeminiClient, GeminiMessage, GeminiRequest, GeminiRole, GeminiSchema, GenerateResponse, Part,
+    Result,
+};
+use core::future::Future;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTool {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    pub description: String,
+    /// Arguments object, left out for functions without arguments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<GeminiSchema>,
+}
+
+impl FunctionDeclaration {
+    pub fn new(name: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            parameters: None,
+        }
+    }
+
+    pub fn parameters(mut self, parameters: GeminiSchema) -> Self {
+        self.parameters = Some(parameters);
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiToolConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_calling_config: Option<FunctionCallingConfig>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionCallingConfig {
+    pub mode: FunctionCallingMode,
+    /// Only meaningful with [`FunctionCallingMode::Any`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_function_names: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FunctionCallingMode {
+    /// Model decides between text and function calls
+    #[default]
+    Auto,
+    /// Model must call a function
+    Any,
+    /// Model must not call functions
+    None,
+}
+
+type Handler<'a> = Box<dyn Fn(Value) -> BoxFuture<'a, Value> + Send + Sync + 'a>;
+
+/// Declared functions and the Rust handlers answering them
+#[derive(Default)]
+pub struct FunctionRegistry<'a> {
+    entries: Vec<(FunctionDeclaration, Handler<'a>)>,
+}
+
+impl<'a> FunctionRegistry<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a function, `handler` receives the call's `args` and returns its `response`
+    pub fn register<F, Fut>(&mut self, declaration: FunctionDeclaration, handler: F) -> &mut Self
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'a,
+        Fut: Future<Output = Value> + Send + 'a,
+    {
+        self.entries
+            .push((declaration, Box::new(move |args| handler(args).boxed())));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Tool declaring every registered function
+    pub fn tool(&self) -> GeminiTool {
+        GeminiTool {
+            function_declarations: self
+                .entries
+                .iter()
+                .map(|(declaration, _handler)| declaration.clone())
+                .collect(),
+        }
+    }
+
+    /// Run the handler for `call`, unknown functions get an `error` response
+    pub async fn call(&self, call: &FunctionCallPart) -> Value {
+        let handler = self
+            .entries
+            .iter()
+            .find(|(declaration, _handler)| declaration.name == call.name);
+
+        match handler {
+            Some((_declaration, handler)) => handler(call.args.clone()).await,
+            None => serde_json::json!({ "error": format!("unknown function {}", call.name) }),
+        }
+    }
+}
+
+/// Outcome of [`GeminiClient::generate_with_tools`]
+#[derive(Clone, Debug)]
+pub struct ToolLoopResponse {
+    /// Last response
+    pub response: GenerateResponse,
+    /// Every executed call with the response sent back
+    pub calls: Vec<(FunctionCallPart, Value)>,
+    /// Loop stopped at the iteration cap while the model still wanted to call functions
+    pub truncated: bool,
+}
+
+impl GeminiClient {
+    /// Generate, answer function calls through `registry` and generate again
+    ///
+    /// Stops once a response contains no function calls, or after
+    /// `max_iterations` generations, whichever comes first. Requests using
+    /// cached content must have the registry's tool cached along with it.
+    pub async fn generate_with_tools(
+        &self,
+        mut request: GeminiRequest,
+        registry: &FunctionRegistry<'_>,
+        max_iterations: usize,
+    ) -> Result<ToolLoopResponse> {
+        if !registry.is_empty() && request.cached_content.is_none() {
+            request.tools.push(registry.tool());
+        }
+
+        let mut calls = Vec::new();
+        let mut response = GenerateResponse::default();
+
+        for _ in 0..max_iterations {
+            response = self.generate(request.clone()).await?;
+
+            let function_calls = response
+                .parts
+                .iter()
+                .filter_map(|part| match part {
+                    Part::TaggedPart(TaggedPart::FunctionCall(call)) => Some(call.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            if function_calls.is_empty() {
+                return Ok(ToolLoopResponse {
+                    response,
+                    calls,
+                    truncated: false,
+                });
+            }
+
+            request.contents.push(GeminiMessage::new(
+                GeminiRole::Model,
+                response.parts.clone(),
+            ));
+
+            let mut responses = Vec::with_capacity(function_calls.len());
+
+            for call in function_calls {
+                let response = registry.call(&call).await;
+
+                tracing::debug!("function call {}({}) -> {response}", call.name, call.args);
+
+                responses.push(Part::from(TaggedPart::FunctionResponse(
+                    FunctionResponsePart {
+                        name: call.name.clone(),
+                        response: response.clone(),
+                    },
+                )));
+
+                calls.push((call, response));
+            }
+
+            request
+                .contents
+                .push(GeminiMessage::new(GeminiRole::User, responses));
+        }
+
+        Ok(ToolLoopResponse {
+            response,
+            calls,
+            truncated: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{client, MockResponse, MockServer};
+    use crate::GeminiSchemaType;
+    use alloc::sync::Arc;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    fn response(parts: Value) -> MockResponse {
+        MockResponse::json(
+            200,
+            json!({"candidates": [{"content": {"role": "model", "parts": parts}}]}),
+        )
+    }
+
+    fn registry(seen: Arc<Mutex<Vec<Value>>>) -> FunctionRegistry<'static> {
+        let mut registry = FunctionRegistry::new();
+
+        registry.register(
+            FunctionDeclaration::new("add", "adds two numbers").parameters(GeminiSchema {
+                properties: [
+                    ("a".into(), GeminiSchema::new(GeminiSchemaType::Integer)),
+                    ("b".into(), GeminiSchema::new(GeminiSchemaType::Integer)),
+                ]
+                .into(),
+                ..GeminiSchema::new(GeminiSchemaType::Object)
+            }),
+            move |args| {
+                seen.lock().unwrap().push(args.clone());
+
+                async move {
+                    json!({ "sum": args["a"].as_i64().unwrap() + args["b"].as_i64().unwrap() })
+                }
+            },
+        );
+
+        registry
+    }
+
+    fn request() -> GeminiRequest {
+        GeminiRequest {
+            contents: vec![GeminiMessage::new(GeminiRole::User, vec!["1 + 2?".into()])],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_calls_until_text() {
+        let server = MockServer::start([
+            response(json!([{"functionCall": {"name": "add", "args": {"a": 1, "b": 2}}}])),
+            response(json!([{"text": "3"}])),
+        ])
+        .await;
+
+        let client = client(&server);
+        let seen = Arc::default();
+
+        let response = client
+            .generate_with_tools(request(), &registry(Arc::clone(&seen)), 4)
+            .await
+            .unwrap();
+
+        assert!(!response.truncated);
+        assert_eq!(response.response.text(), "3");
+        assert_eq!(response.calls.len(), 1);
+        assert_eq!(*seen.lock().unwrap(), [json!({"a": 1, "b": 2})]);
+
+        let requests = server.requests();
+        let first = requests[0].json();
+        let second = requests[1].json();
+
+        assert_eq!(first["tools"][0]["functionDeclarations"][0]["name"], "add");
+        assert_eq!(
+            first["tools"][0]["functionDeclarations"][0]["parameters"]["type"],
+            "OBJECT"
+        );
+        assert_eq!(second["contents"].as_array().unwrap().len(), 3);
+        assert_eq!(second["contents"][1]["role"], "model");
+        assert_eq!(
+            second["contents"][1]["parts"][0]["function_call"]["name"],
+            "add"
+        );
+        assert_eq!(
+            second["contents"][2]["parts"][0]["function_response"],
+            json!({"name": "add", "response": {"sum": 3}})
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_iteration_cap() {
+        let call = || response(json!([{"functionCall": {"name": "missing", "args": {}}}]));
+        let server = MockServer::start([call(), call(), call()]).await;
+
+        let client = client(&server);
+
+        let response = client
+            .generate_with_tools(request(), &registry(Arc::default()), 2)
+            .await
+            .unwrap();
+
+        assert!(response.truncated);
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(response.calls.len(), 2);
+        assert_eq!(response.calls[0].1["error"], "unknown function missing");
+    }
+}
@@ -0,0 +1,311 @@
+use crate::model::MessageId;
+use anyhow::Context as _;
+use google_gemini::content::FunctionCallPart;
+use google_gemini::{FunctionDeclaration, FunctionRegistry, GeminiSchema};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::all::{ChannelId, Context, CreateAttachment, CreateMessage};
+
+/// Something the model can do in a channel, each exposed as a function
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub enum Action {
+    SendMessage,
+    PinMessage,
+    DeleteMessages,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SendMessage {
+    /// Message to reply to
+    #[serde(default)]
+    pub referenced_message: Option<MessageId>,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PinMessage {
+    pub message_id: MessageId,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DeleteMessages {
+    #[serde(default)]
+    pub message_ids: Vec<MessageId>,
+    /// Shown in the audit log
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl Action {
+    pub const ALL: [Self; 3] = [Self::SendMessage, Self::PinMessage, Self::DeleteMessages];
+
+    /// Whether the action needs guild permissions, so is unavailable in DMs
+    pub const fn is_guild_only(self) -> bool {
+        matches!(self, Self::PinMessage | Self::DeleteMessages)
+    }
+
+    pub const fn function_name(self) -> &'static str {
+        match self {
+            Self::SendMessage => "send_message",
+            Self::PinMessage => "pin_message",
+            Self::DeleteMessages => "delete_messages",
+        }
+    }
+
+    const fn description(self) -> &'static str {
+        match self {
+            Self::SendMessage => "send a message to the channel, optionally as a reply",
+            Self::PinMessage => "pin stuff u rly rly like or should remember",
+            Self::DeleteMessages => "only delete if you think its a good idea or mari is testing",
+        }
+    }
+
+    fn parameters(self) -> Value {
+        let schema = match self {
+            Self::SendMessage => schemars::schema_for!(SendMessage),
+            Self::PinMessage => schemars::schema_for!(PinMessage),
+            Self::DeleteMessages => schemars::schema_for!(DeleteMessages),
+        };
+
+        serde_json::to_value(schema).unwrap()
+    }
+
+    pub fn declaration(self) -> FunctionDeclaration {
+        let parameters = GeminiSchema::from_json_schema(&self.parameters())
+            .expect("action parameters convert to a gemini schema");
+
+        FunctionDeclaration::new(self.function_name(), self.description()).parameters(parameters)
+    }
+}
+
+/// Executes actions in the channel being replied to
+pub struct ActionContext<'a> {
+    pub context: &'a Context,
+    pub channel_id: ChannelId,
+}
+
+impl<'a> ActionContext<'a> {
+    /// Register `actions` as functions executed in this channel
+    pub fn register(
+        &'a self,
+        registry: &mut FunctionRegistry<'a>,
+        actions: impl IntoIterator<Item = Action>,
+    ) {
+        for action in actions {
+            registry.register(action.declaration(), move |args| self.execute(action, args));
+        }
+    }
+
+    /// Run `action`, errors are reported back to the model instead of failing the reply
+    pub async fn execute(&self, action: Action, args: Value) -> Value {
+        self.try_execute(action, args)
+            .await
+            .unwrap_or_else(|error| {
+                tracing::error!("{}: {error:#}", action.function_name());
+
+                json!({ "error": format!("{error:#}") })
+            })
+    }
+
+    async fn try_execute(&self, action: Action, args: Value) -> anyhow::Result<Value> {
+        match action {
+            Action::SendMessage => self.send_message(parse(args)?).await,
+            Action::PinMessage => self.pin_message(parse(args)?).await,
+            Action::DeleteMessages => self.delete_messages(parse(args)?).await,
+        }
+    }
+
+    async fn send_message(&self, args: SendMessage) -> anyhow::Result<Value> {
+        let mut builder = CreateMessage::new();
+
+        if args.content.chars().count() > 1950 {
+            builder = builder.add_file(CreateAttachment::bytes(args.content, "message.txt"));
+        } else {
+            builder = builder.content(args.content);
+        }
+
+        if let Some(message_id) = args.referenced_message {
+            builder = builder.reference_message((self.channel_id, message_id.into()));
+        }
+
+        let message = self.channel_id.send_message(self.context, builder).await?;
+
+        Ok(json!({ "sent": true, "message_id": message.id.to_string() }))
+    }
+
+    async fn pin_message(&self, args: PinMessage) -> anyhow::Result<Value> {
+        self.channel_id.pin(self.context, args.message_id).await?;
+
+        Ok(json!({ "pinned": true }))
+    }
+
+    async fn delete_messages(&self, args: DeleteMessages) -> anyhow::Result<Value> {
+        let mut deleted = Vec::new();
+
+        if let Some(messages) = self.context.cache.channel_messages(self.channel_id) {
+            for message_id in args.message_ids {
+                if messages.contains_key(&message_id.into()) {
+                    deleted.push(serenity::model::id::MessageId::from(message_id));
+                }
+            }
+        }
+
+        let reason = Some(args.reason.as_str()).filter(|reason| !reason.is_empty());
+
+        for message_ids in deleted.chunks(100) {
+            match message_ids {
+                [] => continue,
+                [message_id] => {
+                    self.context
+                        .http
+                        .delete_message(self.channel_id, *message_id, reason)
+                        .await?;
+                }
+                message_ids => {
+                    let map = json!({ "messages": message_ids });
+
+                    self.context
+                        .http
+                        .delete_messages(self.channel_id, &map, reason)
+                        .await?;
+                }
+            }
+        }
+
+        let deleted = deleted
+            .into_iter()
+            .map(|message_id| message_id.to_string())
+            .collect::<Vec<_>>();
+
+        Ok(json!({ "deleted": deleted }))
+    }
+}
+
+/// Plain text the model answered with, to be sent as if it had called send_message
+///
+/// Nothing is relayed if it already sent a message itself, or if sending isn't `allowed`.
+pub fn relayed_text<'a>(
+    allowed: &[Action],
+    calls: &[(FunctionCallPart, Value)],
+    text: &'a str,
+) -> Option<&'a str> {
+    let has_sent = calls.iter().any(|(call, result)| {
+        call.name == Action::SendMessage.function_name() && result["sent"] == true
+    });
+
+    if has_sent || text.trim().is_empty() {
+        return None;
+    }
+
+    if !allowed.contains(&Action::SendMessage) {
+        tracing::info!("dropping text answer, SendMessage isn't allowed here");
+
+        return None;
+    }
+
+    Some(text)
+}
+
+fn parse<T: DeserializeOwned>(args: Value) -> anyhow::Result<T> {
+    serde_json::from_value(args).context("invalid arguments")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use google_gemini::GeminiSchemaType;
+
+    #[test]
+    fn declarations() {
+        for action in Action::ALL {
+            let declaration = action.declaration();
+            let parameters = declaration.parameters.unwrap();
+
+            assert_eq!(declaration.name, action.function_name());
+            assert_eq!(parameters.schema_type, Some(GeminiSchemaType::Object));
+        }
+
+        let parameters = Action::SendMessage.declaration().parameters.unwrap();
+        let referenced_message = &parameters.properties["referenced_message"];
+
+        assert_eq!(parameters.required, ["content"]);
+        assert_eq!(
+            referenced_message.schema_type,
+            Some(GeminiSchemaType::String)
+        );
+        assert_eq!(referenced_message.nullable, Some(true));
+
+        let parameters = Action::DeleteMessages.declaration().parameters.unwrap();
+        let message_ids = parameters.properties["message_ids"]
+            .items
+            .as_deref()
+            .unwrap();
+
+        assert_eq!(message_ids.schema_type, Some(GeminiSchemaType::String));
+    }
+
+    #[test]
+    fn guild_only() {
+        let in_dms = Action::ALL
+            .into_iter()
+            .filter(|action| !action.is_guild_only())
+            .collect::<Vec<_>>();
+
+        assert_eq!(in_dms, [Action::SendMessage]);
+    }
+
+    #[test]
+    fn relays_text() {
+        let send = |sent| {
+            let call = FunctionCallPart {
+                name: Action::SendMessage.function_name().into(),
+                args: json!({"content": "hi"}),
+            };
+
+            (call, json!({ "sent": sent }))
+        };
+
+        assert_eq!(relayed_text(&Action::ALL, &[], "hi"), Some("hi"));
+        assert_eq!(relayed_text(&Action::ALL, &[], " \n"), None);
+        assert_eq!(relayed_text(&Action::ALL, &[send(true)], "hi"), None);
+        assert_eq!(relayed_text(&Action::ALL, &[send(false)], "hi"), Some("hi"));
+
+        // channels whose actions leave out SendMessage
+        assert_eq!(relayed_text(&[Action::PinMessage], &[], "hi"), None);
+        assert_eq!(relayed_text(&[], &[], "hi"), None);
+    }
+
+    #[test]
+    fn parse_arguments() {
+        let args = parse::<SendMessage>(json!({"content": "hi", "referenced_message": 1})).unwrap();
+
+        assert_eq!(args.content, "hi");
+        assert_eq!(args.referenced_message.map(MessageId::get), Some(1));
+
+        let error = parse::<PinMessage>(json!({"message_id": "nope"})).unwrap_err();
+
+        assert!(format!("{error:#}").starts_with("invalid arguments"));
+    }
+
+    #[test]
+    fn parse_snowflakes() {
+        // above 2^53, so not representable as a double
+        let snowflake = 1317078903348793435;
+
+        let args = parse::<PinMessage>(json!({"message_id": snowflake.to_string()})).unwrap();
+
+        assert_eq!(args.message_id.get(), snowflake);
+
+        let args =
+            parse::<DeleteMessages>(json!({"message_ids": [snowflake.to_string()]})).unwrap();
+
+        assert_eq!(args.message_ids[0].get(), snowflake);
+
+        // what protobuf Struct arguments turn integers into
+        let error = parse::<PinMessage>(json!({"message_id": snowflake as f64})).unwrap_err();
+
+        assert!(format!("{error:#}").contains("must be passed as strings"));
+    }
+}
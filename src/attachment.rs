@@ -1,15 +1,23 @@
 use crate::Claide;
 use alloc::borrow::Cow;
 use anyhow::Context;
-use google_gemini::content::TaggedPart;
-use google_gemini::{FileDataPart, GeminiClient, Part};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use core::sync::atomic::{AtomicU64, Ordering};
+use google_gemini::content::{InlineDataPart, TaggedPart};
+use google_gemini::{FileDataPart, GeminiClient, GeminiFileMetadata, Part};
 use mime::Mime;
 use reqwest::header::CONTENT_TYPE;
-use reqwest::Url;
+use reqwest::{Response, Url};
+use std::io;
+use time::{Duration, OffsetDateTime};
+use tokio_util::io::StreamReader;
 
 const DEFAULT_MIME: &str = "text/plain";
 const DEFAULT_FILE_NAME: &str = "file.txt";
 
+/// Re-upload this long before expiry so files don't vanish mid-request
+const EXPIRY_MARGIN: Duration = Duration::minutes(10);
+
 fn sanitize_content_type(content_type: Cow<'_, str>) -> anyhow::Result<Cow<'static, str>> {
     let mime = content_type.parse::<Mime>().context("parsing mime")?;
 
@@ -28,11 +36,21 @@ fn sanitize_content_type(content_type: Cow<'_, str>) -> anyhow::Result<Cow<'stat
     Ok(sanitized_content_type)
 }
 
+/// Attachment data, either fully downloaded or still streaming in
+#[derive(Debug)]
+enum AttachmentBody {
+    Bytes(Vec<u8>),
+    Stream {
+        content_length: u64,
+        response: Response,
+    },
+}
+
 #[derive(Debug)]
 pub struct AttachmentContent<'a> {
     content_type: Cow<'static, str>,
     file_name: Option<&'a str>,
-    bytes: Vec<u8>,
+    body: AttachmentBody,
 }
 
 impl Default for AttachmentContent<'_> {
@@ -40,7 +58,7 @@ impl Default for AttachmentContent<'_> {
         Self {
             content_type: DEFAULT_MIME.into(),
             file_name: None,
-            bytes: "[empty]".into(),
+            body: AttachmentBody::Bytes("[empty]".into()),
         }
     }
 }
@@ -49,61 +67,171 @@ impl<'a> AttachmentContent<'a> {
     fn new(
         content_type: Option<Cow<'a, str>>,
         file_name: Option<&'a str>,
-        bytes: Vec<u8>,
+        body: AttachmentBody,
     ) -> anyhow::Result<Self> {
         Ok(Self {
             content_type: sanitize_content_type(
                 content_type.unwrap_or(Cow::Borrowed(DEFAULT_MIME)),
             )?,
             file_name,
-            bytes,
+            body,
         })
     }
 
-    async fn upload(self, gemini: &GeminiClient) -> anyhow::Result<String> {
+    fn len(&self) -> u64 {
+        match &self.body {
+            AttachmentBody::Bytes(bytes) => bytes.len() as u64,
+            AttachmentBody::Stream { content_length, .. } => *content_length,
+        }
+    }
+
+    /// Whether this can be sent inline instead of going through the Files API
+    fn is_inlinable(&self, limit: u64) -> bool {
+        self.len() <= limit
+            && self
+                .content_type
+                .parse::<Mime>()
+                .is_ok_and(|mime| google_gemini::is_supported_mime(&mime))
+    }
+
+    async fn into_inline(self) -> anyhow::Result<GeminiAttachment> {
+        let bytes = match self.body {
+            AttachmentBody::Bytes(bytes) => bytes,
+            AttachmentBody::Stream { response, .. } => response.bytes().await?.to_vec(),
+        };
+
+        tracing::info!("inlining {} bytes of {}", bytes.len(), self.content_type);
+
+        Ok(GeminiAttachment::Inline {
+            data: BASE64_STANDARD.encode(bytes),
+            content_type: self.content_type,
+        })
+    }
+
+    async fn upload(self, gemini: &GeminiClient) -> anyhow::Result<GeminiFileMetadata> {
         let file_name = self.file_name.unwrap_or(DEFAULT_FILE_NAME);
 
         tracing::info!("uploading to gemini: {} - {}", file_name, self.content_type);
 
-        let content_size = self.bytes.len() as u32;
+        match self.body {
+            AttachmentBody::Bytes(bytes) => {
+                let url = gemini
+                    .create_file(file_name, bytes.len() as u64, &self.content_type)
+                    .await?;
+
+                Ok(gemini.upload_file(&url, &bytes).await?)
+            }
+            AttachmentBody::Stream {
+                content_length,
+                response,
+            } => {
+                let url = gemini
+                    .create_file(file_name, content_length, &self.content_type)
+                    .await?;
 
-        let url = gemini
-            .create_file(file_name, content_size, &self.content_type)
-            .await?;
+                let chunks = futures_util::stream::try_unfold(response, |mut response| async {
+                    let chunk = response.chunk().await.map_err(io::Error::other)?;
 
-        gemini.upload_file(url, content_size, self.bytes).await
+                    Ok::<_, io::Error>(chunk.map(|chunk| (chunk, response)))
+                });
+
+                let reader = StreamReader::new(chunks);
+
+                tokio::pin!(reader);
+
+                Ok(gemini
+                    .upload_file_from_reader(&url, content_length, reader)
+                    .await?)
+            }
+        }
     }
 }
 
-/// Registered and uploaded file reference
+/// Attachment as sent to gemini
 #[derive(Debug, Clone)]
-pub struct GeminiAttachment {
-    pub uri: String,
-    pub content_type: Cow<'static, str>,
+pub enum GeminiAttachment {
+    /// Uploaded through the Files API
+    File {
+        uri: String,
+        content_type: Cow<'static, str>,
+        expiration_time: Option<OffsetDateTime>,
+    },
+    /// Small enough to be sent along, base64 encoded
+    Inline {
+        data: String,
+        content_type: Cow<'static, str>,
+    },
+}
+
+impl GeminiAttachment {
+    /// Whether the file is gone, or about to be, at `now`
+    pub fn is_expired_at(&self, now: OffsetDateTime) -> bool {
+        match self {
+            Self::File {
+                expiration_time, ..
+            } => expiration_time
+                .is_some_and(|expiration_time| expiration_time - EXPIRY_MARGIN <= now),
+            Self::Inline { .. } => false,
+        }
+    }
+}
+
+/// Bytes still allowed inline in one request, keeps it under the API's size limit
+#[derive(Debug)]
+pub struct InlineBudget(AtomicU64);
+
+impl InlineBudget {
+    pub fn new(bytes: u64) -> Self {
+        Self(AtomicU64::new(bytes))
+    }
+
+    /// Reserve `len` bytes, fails once the budget is used up
+    fn take(&self, len: u64) -> bool {
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(len)
+            })
+            .is_ok()
+    }
 }
 
 /// Uploadable to gemini
 pub trait GeminiUpload {
     /// Download file data
-    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent>;
+    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent<'_>>;
 
-    /// Upload this to gemini
-    async fn upload_into_gemini(&self, claide: &Claide) -> anyhow::Result<GeminiAttachment> {
+    /// Upload this to gemini, or inline it if it is small enough and `inline_budget` allows
+    async fn upload_into_gemini(
+        &self,
+        claide: &Claide,
+        inline_budget: &InlineBudget,
+    ) -> anyhow::Result<GeminiAttachment> {
         let content = self
             .fetch_content(claide)
             .await
             .inspect_err(|err| tracing::warn!("fetch failed: {err}"))
             .unwrap_or_default();
+
+        if content.is_inlinable(claide.settings.gemini.inline_attachment_limit)
+            && inline_budget.take(content.len())
+        {
+            return content.into_inline().await;
+        }
+
         let content_type = content.content_type.clone();
-        let uri = content.upload(&claide.gemini).await?;
+        let file = content.upload(&claide.gemini).await?;
 
-        Ok(GeminiAttachment { uri, content_type })
+        Ok(GeminiAttachment::File {
+            uri: file.uri,
+            content_type,
+            expiration_time: file.expiration_time,
+        })
     }
 }
 
 impl GeminiUpload for Url {
-    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent> {
-        let file_name = self.path_segments().and_then(|path| path.last());
+    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent<'_>> {
+        let file_name = self.path_segments().and_then(|mut path| path.next_back());
 
         tracing::info!("downloading from url: {file_name:?}: {self}");
         let resp = claide
@@ -120,7 +248,16 @@ impl GeminiUpload for Url {
             .map(ToOwned::to_owned)
             .map(Cow::Owned);
 
-        AttachmentContent::new(content_type, file_name, resp.bytes().await?.to_vec())
+        // stream large files straight into the upload instead of buffering them
+        let body = match resp.content_length() {
+            Some(content_length) => AttachmentBody::Stream {
+                content_length,
+                response: resp,
+            },
+            None => AttachmentBody::Bytes(resp.bytes().await?.to_vec()),
+        };
+
+        AttachmentContent::new(content_type, file_name, body)
     }
 }
 
@@ -133,16 +270,103 @@ impl Attachment {
 }
 
 impl GeminiUpload for Attachment {
-    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent> {
+    async fn fetch_content(&self, claide: &Claide) -> anyhow::Result<AttachmentContent<'_>> {
         self.0.fetch_content(claide).await
     }
 }
 
 impl From<GeminiAttachment> for Part {
     fn from(value: GeminiAttachment) -> Self {
-        Self::TaggedPart(TaggedPart::FileData(FileDataPart {
-            mime_type: value.content_type.to_string(),
-            file_uri: value.uri,
-        }))
+        match value {
+            GeminiAttachment::File {
+                uri, content_type, ..
+            } => Self::TaggedPart(TaggedPart::FileData(FileDataPart {
+                mime_type: content_type.into_owned(),
+                file_uri: uri,
+            })),
+            GeminiAttachment::Inline { data, content_type } => {
+                Self::TaggedPart(TaggedPart::InlineData(InlineDataPart {
+                    mime_type: content_type.into_owned(),
+                    data,
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content<'a>(content_type: &'a str, bytes: &[u8]) -> AttachmentContent<'a> {
+        AttachmentContent::new(
+            Some(content_type.into()),
+            None,
+            AttachmentBody::Bytes(bytes.to_vec()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn expiry() {
+        let now = OffsetDateTime::now_utc();
+        let file = |expiration_time| GeminiAttachment::File {
+            uri: "files/abc".into(),
+            content_type: DEFAULT_MIME.into(),
+            expiration_time,
+        };
+
+        assert!(!file(None).is_expired_at(now));
+        assert!(!file(Some(now + Duration::hours(48))).is_expired_at(now));
+        assert!(file(Some(now + Duration::minutes(5))).is_expired_at(now));
+    }
+
+    #[tokio::test]
+    async fn small_attachments_are_inlined() {
+        let content = content("image/png; charset=binary", b"\x89PNG");
+
+        assert!(content.is_inlinable(1024));
+
+        let attachment = content.into_inline().await.unwrap();
+
+        assert!(!attachment.is_expired_at(OffsetDateTime::now_utc()));
+
+        let Part::TaggedPart(TaggedPart::InlineData(part)) = Part::from(attachment) else {
+            panic!("expected inline data");
+        };
+
+        assert_eq!(part.mime_type, "image/png");
+        assert_eq!(part.data, "iVBORw==");
+    }
+
+    #[test]
+    fn inline_budget() {
+        let budget = InlineBudget::new(10);
+
+        assert!(budget.take(6));
+        assert!(!budget.take(6));
+        assert!(budget.take(4));
+        assert!(!budget.take(1));
+        assert!(budget.take(0));
+    }
+
+    #[test]
+    fn large_or_unsupported_attachments_are_uploaded() {
+        assert!(!content("image/png", &[0; 2048]).is_inlinable(1024));
+        assert!(!content("application/zip", b"PK").is_inlinable(1024));
+        assert!(!content("image/png", b"\x89PNG").is_inlinable(0));
+
+        let attachment = GeminiAttachment::File {
+            uri: "files/abc".into(),
+            content_type: "video/mp4".into(),
+            expiration_time: None,
+        };
+
+        let Part::TaggedPart(TaggedPart::FileData(part)) = Part::from(attachment) else {
+            panic!("expected file data");
+        };
+
+        assert_eq!(part.file_uri, "files/abc");
+        assert_eq!(part.mime_type, "video/mp4");
     }
 }
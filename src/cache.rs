@@ -0,0 +1,256 @@
+use crate::lock::ChannelLocks;
+use crate::settings::CacheSettings;
+use core::hash::{Hash, Hasher};
+use core::time::Duration;
+use google_gemini::{estimate_tokens, GeminiClient, GeminiError, GeminiRequest};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serenity::all::ChannelId;
+use std::hash::DefaultHasher;
+use time::OffsetDateTime;
+
+/// Cache of a channel's system instruction, tools and older history
+#[derive(Debug)]
+struct ChannelCache {
+    name: String,
+    /// Hash of the model, system instruction and tools the cache was made with
+    key: u64,
+    /// Hash of every cached message, oldest first
+    messages: Vec<u64>,
+    expire_time: Option<OffsetDateTime>,
+}
+
+/// Keeps per-channel `cachedContents` so stable prompt prefixes aren't resent
+pub struct ContextCache {
+    settings: CacheSettings,
+    channels: ChannelLocks<ChannelCache>,
+}
+
+impl ContextCache {
+    pub fn new(settings: CacheSettings) -> Self {
+        Self {
+            settings,
+            channels: ChannelLocks::default(),
+        }
+    }
+
+    /// Move the system instruction, tools and older contents of `request` into a cache
+    ///
+    /// Returns whether `request` now refers to a cache. Failures only cost the saving.
+    pub async fn apply(
+        &self,
+        gemini: &GeminiClient,
+        channel_id: ChannelId,
+        request: &mut GeminiRequest,
+    ) -> bool {
+        let mut cacheable = cacheable(request);
+
+        let key = hash(&(
+            cacheable.model.as_deref().unwrap_or(gemini.model()),
+            &cacheable.system_instruction,
+            &cacheable.tools,
+            &cacheable.tool_config,
+        ));
+
+        let messages = request.contents.iter().map(hash).collect::<Vec<_>>();
+        let ttl = Duration::from_secs(self.settings.ttl_secs);
+        let now = OffsetDateTime::now_utc();
+        let mut channel = self.channels.lock(channel_id).await;
+
+        let cached = channel.as_ref().and_then(|cache| {
+            let usable = cache.key == key
+                && cache
+                    .expire_time
+                    .is_none_or(|expire_time| expire_time > now);
+
+            let covered = covered(&cache.messages, &messages)?;
+            let uncached = messages.len() - covered;
+
+            (usable && uncached <= self.settings.uncached_messages * 2).then_some(covered)
+        });
+
+        let covered = match cached {
+            Some(covered) => {
+                let cache = channel.as_mut().unwrap();
+
+                // keep caches that are still used alive
+                if cache
+                    .expire_time
+                    .is_some_and(|expire_time| expire_time - ttl / 2 < now)
+                {
+                    match gemini.update_cached_content(&cache.name, ttl).await {
+                        Ok(updated) => cache.expire_time = updated.expire_time,
+                        Err(error) => tracing::warn!("failed to extend {}: {error}", cache.name),
+                    }
+                }
+
+                covered
+            }
+            None => {
+                if let Some(old) = channel.take() {
+                    if let Err(error) = gemini.delete_cached_content(&old.name).await {
+                        tracing::warn!("failed to delete {}: {error}", old.name);
+                    }
+                }
+
+                let prefix = messages
+                    .len()
+                    .saturating_sub(self.settings.uncached_messages.max(1));
+
+                cacheable.contents.truncate(prefix);
+
+                let tokens =
+                    estimate_tokens(&serde_json::to_vec(&cacheable.contents).unwrap_or_default());
+
+                if prefix == 0 || tokens < self.settings.min_tokens {
+                    return false;
+                }
+
+                let cache = match gemini.create_cached_content(&cacheable, ttl).await {
+                    Ok(cache) => cache,
+                    Err(error) => {
+                        tracing::warn!("failed to cache {channel_id} context: {error}");
+
+                        return false;
+                    }
+                };
+
+                tracing::info!("cached {prefix} messages of {channel_id} as {}", cache.name);
+
+                *channel = Some(ChannelCache {
+                    name: cache.name,
+                    key,
+                    messages: messages[..prefix].to_vec(),
+                    expire_time: cache.expire_time,
+                });
+
+                prefix
+            }
+        };
+
+        request.cached_content = channel.as_ref().map(|cache| cache.name.clone());
+        request.system_instruction = None;
+        request.tools.clear();
+        request.tool_config = None;
+        request.contents.drain(..covered);
+
+        true
+    }
+
+    /// Delete the cache of `channel_id`, e.g. after the API rejected it
+    pub async fn invalidate(&self, gemini: &GeminiClient, channel_id: ChannelId) {
+        let Some(old) = self.channels.lock(channel_id).await.take() else {
+            return;
+        };
+
+        if let Err(error) = gemini.delete_cached_content(&old.name).await {
+            tracing::warn!("failed to delete {}: {error}", old.name);
+        }
+    }
+}
+
+/// Whether a request failed because of its cache, e.g. it expired or was deleted
+///
+/// Rate limits and server errors say nothing about the cache, so it's kept then.
+pub fn is_cache_error(error: &GeminiError) -> bool {
+    let Some(status) = error.status() else {
+        return false;
+    };
+
+    let mentions_cache = error
+        .api_error()
+        .is_some_and(|error| error.message.to_lowercase().contains("cache"));
+
+    status.is_client_error()
+        && status != StatusCode::TOO_MANY_REQUESTS
+        && (status == StatusCode::NOT_FOUND || status == StatusCode::FORBIDDEN || mentions_cache)
+}
+
+fn hash(value: &impl Serialize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    serde_json::to_vec(value)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Copy of `request` to be cached, without tools that declare no functions
+fn cacheable(request: &GeminiRequest) -> GeminiRequest {
+    let mut cacheable = request.clone();
+
+    cacheable
+        .tools
+        .retain(|tool| !tool.function_declarations.is_empty());
+
+    cacheable
+}
+
+/// How many leading `messages` the `cached` ones cover
+///
+/// The oldest messages may have scrolled out of the channel since the cache was
+/// made, so `messages` only has to start with the tail of `cached`. At least one
+/// message is always left uncovered.
+fn covered(cached: &[u64], messages: &[u64]) -> Option<usize> {
+    let last = cached.last()?;
+    let end = messages.iter().position(|message| message == last)? + 1;
+
+    (end < messages.len() && cached.ends_with(&messages[..end])).then_some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use google_gemini::{FunctionDeclaration, GeminiApiError, GeminiTool};
+
+    #[test]
+    fn covered_prefix() {
+        assert_eq!(covered(&[1, 2, 3], &[1, 2, 3, 4, 5]), Some(3));
+        // oldest messages scrolled out
+        assert_eq!(covered(&[1, 2, 3], &[2, 3, 4]), Some(2));
+        // newest message is never cached
+        assert_eq!(covered(&[1, 2, 3], &[1, 2, 3]), None);
+        // history was edited
+        assert_eq!(covered(&[1, 2, 3], &[1, 7, 3, 4]), None);
+        assert_eq!(covered(&[1, 2, 3], &[4, 5]), None);
+        assert_eq!(covered(&[], &[1, 2]), None);
+    }
+
+    #[test]
+    fn cacheable_tools() {
+        let tool = GeminiTool {
+            function_declarations: vec![FunctionDeclaration::new("pin", "pin it")],
+        };
+        let request = GeminiRequest {
+            tools: vec![GeminiTool::default(), tool],
+            ..Default::default()
+        };
+
+        assert_eq!(cacheable(&request).tools.len(), 1);
+        assert!(cacheable(&GeminiRequest::default()).tools.is_empty());
+    }
+
+    #[test]
+    fn cache_errors() {
+        let error = |status: u16, message: &str| GeminiError::Status {
+            status: StatusCode::from_u16(status).unwrap(),
+            error: GeminiApiError {
+                code: status,
+                message: message.into(),
+                ..Default::default()
+            },
+            retry_after: None,
+        };
+
+        assert!(is_cache_error(&error(404, "not found")));
+        assert!(is_cache_error(&error(403, "permission denied")));
+        assert!(is_cache_error(&error(400, "CachedContent has expired")));
+        assert!(!is_cache_error(&error(400, "invalid argument")));
+        assert!(!is_cache_error(&error(429, "cached content quota")));
+        assert!(!is_cache_error(&error(503, "overloaded")));
+        assert!(!is_cache_error(&GeminiError::Blocked {
+            reason: "SAFETY".into()
+        }));
+    }
+}
@@ -0,0 +1,250 @@
+use google_gemini::{estimate_tokens, GeminiClient, GeminiMessage, GeminiRequest};
+use serde::Serialize;
+
+/// Anything that can tell how many tokens a request uses
+pub trait TokenCounter {
+    async fn count_tokens(&self, request: &GeminiRequest) -> anyhow::Result<u32>;
+}
+
+impl TokenCounter for GeminiClient {
+    async fn count_tokens(&self, request: &GeminiRequest) -> anyhow::Result<u32> {
+        Ok(GeminiClient::count_tokens(self, request)
+            .await?
+            .total_tokens)
+    }
+}
+
+/// Drop the oldest contents until `request` fits into `budget` tokens
+///
+/// The newest message is always kept, and so is the one at index `pinned`, e.g. the
+/// message being replied to. Messages are sized locally, scaled to the counted total,
+/// and the cut is verified with another count, so it usually takes two calls. Being
+/// an estimate, the cut may take a message more than strictly needed.
+/// Returns the dropped messages, oldest first.
+pub async fn fit_to_budget(
+    counter: &impl TokenCounter,
+    request: &mut GeminiRequest,
+    budget: u32,
+    pinned: Option<usize>,
+) -> anyhow::Result<Vec<GeminiMessage>> {
+    let newest = request.contents.len().saturating_sub(1);
+    let estimates = request.contents.iter().map(estimate).collect::<Vec<_>>();
+
+    let mut tokens = counter.count_tokens(request).await?;
+    let mut candidate = request.clone();
+    let mut cut = 0;
+
+    while tokens > budget && cut < newest {
+        // what a locally estimated token turned out to be worth
+        let scale = f64::from(tokens) / f64::from(estimate(&candidate).max(1));
+        let mut excess = f64::from(tokens - budget);
+
+        while excess > 0.0 && cut < newest {
+            if Some(cut) != pinned {
+                excess -= scale * f64::from(estimates[cut]);
+            }
+
+            cut += 1;
+        }
+
+        candidate.contents = pinned
+            .filter(|pinned| *pinned < cut)
+            .map(|pinned| &request.contents[pinned])
+            .into_iter()
+            .chain(&request.contents[cut..])
+            .cloned()
+            .collect();
+
+        tokens = counter.count_tokens(&candidate).await?;
+    }
+
+    if cut == 0 {
+        return Ok(Vec::new());
+    }
+
+    tracing::debug!("dropping {cut} oldest messages to fit {budget} tokens");
+
+    let mut dropped = request.contents.drain(..cut).collect::<Vec<_>>();
+
+    if let Some(pinned) = pinned.filter(|pinned| *pinned < cut) {
+        request.contents.insert(0, dropped.remove(pinned));
+    }
+
+    Ok(dropped)
+}
+
+/// Rough token count of `value` as sent, see [`estimate_tokens`]
+pub fn estimate(value: &impl Serialize) -> u32 {
+    serde_json::to_vec(value)
+        .map(|body| estimate_tokens(&body))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use google_gemini::content::{InlineDataPart, TaggedPart};
+    use google_gemini::{GeminiRole, Part};
+
+    /// One token per character of text
+    #[derive(Default)]
+    struct CharCounter {
+        calls: AtomicUsize,
+    }
+
+    impl TokenCounter for CharCounter {
+        async fn count_tokens(&self, request: &GeminiRequest) -> anyhow::Result<u32> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            let count = request
+                .contents
+                .iter()
+                .flat_map(|message| &message.parts)
+                .map(|part| match part {
+                    Part::Text(text) => text.text.len(),
+                    Part::TaggedPart(_) => 0,
+                })
+                .sum::<usize>();
+
+            Ok(count as u32)
+        }
+    }
+
+    fn request(messages: &[String]) -> GeminiRequest {
+        GeminiRequest {
+            contents: messages
+                .iter()
+                .map(|text| GeminiMessage::new(GeminiRole::User, vec![Part::from(text.as_str())]))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// `len` times `letter`, so messages are told apart by their first letter
+    fn text(letter: char, len: usize) -> String {
+        letter.to_string().repeat(len)
+    }
+
+    fn letters(messages: &[GeminiMessage]) -> String {
+        messages
+            .iter()
+            .flat_map(|message| &message.parts)
+            .map(|part| match part {
+                Part::Text(text) => text.text.chars().next().unwrap_or_default(),
+                Part::TaggedPart(_) => '#',
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn fits_already() {
+        let counter = CharCounter::default();
+        let mut request = request(&[text('a', 400), text('b', 400)]);
+
+        let dropped = fit_to_budget(&counter, &mut request, 800, None)
+            .await
+            .unwrap();
+
+        assert!(dropped.is_empty());
+        assert_eq!(request.contents.len(), 2);
+        assert_eq!(counter.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_oldest() {
+        let counter = CharCounter::default();
+        let mut request = request(&[
+            text('a', 400),
+            text('b', 400),
+            text('c', 400),
+            text('d', 200),
+            text('e', 100),
+        ]);
+
+        let dropped = fit_to_budget(&counter, &mut request, 800, None)
+            .await
+            .unwrap();
+
+        assert_eq!(letters(&dropped), "ab");
+        assert_eq!(letters(&request.contents), "cde");
+        // counted once, then verified once
+        assert_eq!(counter.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn counts_again_if_estimate_was_off() {
+        let counter = CharCounter::default();
+        let mut request = request(&[text('a', 400), text('b', 400), text('c', 400)]);
+
+        // large on the wire, but cheap in tokens
+        let image = TaggedPart::InlineData(InlineDataPart {
+            mime_type: "image/png".into(),
+            data: "A".repeat(4000),
+        });
+
+        request.contents.insert(
+            0,
+            GeminiMessage::new(GeminiRole::User, vec![Part::TaggedPart(image)]),
+        );
+
+        let dropped = fit_to_budget(&counter, &mut request, 500, None)
+            .await
+            .unwrap();
+
+        assert_eq!(letters(&dropped), "#ab");
+        assert_eq!(letters(&request.contents), "c");
+        assert_eq!(counter.calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_message() {
+        let counter = CharCounter::default();
+        let mut request = request(&[text('a', 400), text('w', 2000)]);
+
+        let dropped = fit_to_budget(&counter, &mut request, 100, None)
+            .await
+            .unwrap();
+
+        assert_eq!(letters(&dropped), "a");
+        assert_eq!(letters(&request.contents), "w");
+    }
+
+    #[tokio::test]
+    async fn keeps_pinned_message() {
+        let counter = CharCounter::default();
+        let mut request = request(&[
+            text('a', 400),
+            text('b', 200),
+            text('c', 400),
+            text('d', 200),
+            text('e', 100),
+        ]);
+
+        let dropped = fit_to_budget(&counter, &mut request, 700, Some(1))
+            .await
+            .unwrap();
+
+        assert_eq!(letters(&dropped), "ac");
+        assert_eq!(letters(&request.contents), "bde");
+    }
+
+    #[tokio::test]
+    async fn pinned_message_after_cut() {
+        let counter = CharCounter::default();
+        let mut request = request(&[
+            text('a', 400),
+            text('b', 400),
+            text('c', 400),
+            text('d', 200),
+            text('e', 100),
+        ]);
+
+        let dropped = fit_to_budget(&counter, &mut request, 800, Some(3))
+            .await
+            .unwrap();
+
+        assert_eq!(letters(&dropped), "ab");
+        assert_eq!(letters(&request.contents), "cde");
+    }
+}
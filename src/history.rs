@@ -0,0 +1,434 @@
+use crate::lock::ChannelLocks;
+use alloc::collections::BTreeMap;
+use serenity::all::{
+    ChannelId, GetMessages, Http, Message, MessageFlags, MessageId, MessageReferenceKind,
+};
+
+/// Most messages Discord returns per request
+const PAGE_SIZE: usize = 100;
+
+/// Anything that can fetch older messages of a channel
+pub trait HistorySource {
+    /// Up to `limit` messages before `before` (or the newest ones), newest first
+    async fn fetch_messages(
+        &self,
+        channel_id: ChannelId,
+        before: Option<MessageId>,
+        limit: u8,
+    ) -> anyhow::Result<Vec<Message>>;
+
+    async fn fetch_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> anyhow::Result<Message>;
+}
+
+impl HistorySource for Http {
+    async fn fetch_messages(
+        &self,
+        channel_id: ChannelId,
+        before: Option<MessageId>,
+        limit: u8,
+    ) -> anyhow::Result<Vec<Message>> {
+        let mut builder = GetMessages::new().limit(limit);
+
+        if let Some(before) = before {
+            builder = builder.before(before);
+        }
+
+        Ok(channel_id.messages(self, builder).await?)
+    }
+
+    async fn fetch_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> anyhow::Result<Message> {
+        Ok(channel_id.message(self, message_id).await?)
+    }
+}
+
+/// Remembers backfilled messages, so each channel is fetched at most once
+///
+/// Backfilled messages never make it into the serenity cache, so without this a
+/// quiet channel would be fetched again on every trigger. Only messages older than
+/// the cached ones are kept, the cache has the latest version of anything newer.
+#[derive(Default)]
+pub struct Backfill {
+    /// Messages fetched per channel, `None` until fetched successfully
+    channels: ChannelLocks<Vec<Message>>,
+}
+
+impl Backfill {
+    /// `cached` merged with older messages, fetched on the first call per channel
+    pub async fn messages(
+        &self,
+        source: &impl HistorySource,
+        channel_id: ChannelId,
+        cached: Vec<Message>,
+        count: usize,
+    ) -> anyhow::Result<Vec<Message>> {
+        let mut fetched = self.channels.lock(channel_id).await;
+
+        if fetched.is_none() {
+            *fetched = Some(backfill(source, channel_id, cached.clone(), count).await?);
+        }
+
+        let older = fetched.get_or_insert_default();
+
+        // the cache caught up, so older messages aren't needed anymore
+        if cached.len() >= count {
+            older.clear();
+        }
+
+        if let Some(oldest) = cached.iter().map(|message| message.id).min() {
+            older.retain(|message| message.id < oldest);
+        }
+
+        let mut messages = older.clone();
+
+        messages.extend(cached);
+        messages.sort_unstable_by_key(|message| message.id);
+
+        Ok(messages)
+    }
+
+    /// Drop deleted messages, so they don't come back into the context
+    pub async fn forget(&self, channel_id: ChannelId, message_ids: &[MessageId]) {
+        let Some(mut fetched) = self.channels.lock_existing(channel_id).await else {
+            return;
+        };
+
+        if let Some(fetched) = fetched.as_mut() {
+            fetched.retain(|message| !message_ids.contains(&message.id));
+        }
+    }
+}
+
+/// Fill in the message `message` replies to if Discord left it out, e.g. when it's old
+///
+/// Only replies within the same channel are fetched, not forwards or deleted messages.
+pub async fn resolve_reply(
+    source: &impl HistorySource,
+    message: &mut Message,
+) -> anyhow::Result<()> {
+    let Some(reference) = &message.message_reference else {
+        return Ok(());
+    };
+
+    let Some(message_id) = reference.message_id else {
+        return Ok(());
+    };
+
+    let is_deleted = message
+        .flags
+        .is_some_and(|flags| flags.contains(MessageFlags::SOURCE_MESSAGE_DELETED));
+
+    if message.referenced_message.is_some()
+        || is_deleted
+        || reference.kind != MessageReferenceKind::Default
+        || reference.channel_id != message.channel_id
+    {
+        return Ok(());
+    }
+
+    let referenced = source.fetch_message(message.channel_id, message_id).await?;
+
+    message.referenced_message = Some(Box::new(referenced));
+
+    Ok(())
+}
+
+/// Top `cached` up to the last `count` messages of the channel, oldest first
+///
+/// Nothing is fetched if the cache already holds `count` messages, e.g. once the
+/// bot has been running for a while.
+async fn backfill(
+    source: &impl HistorySource,
+    channel_id: ChannelId,
+    cached: Vec<Message>,
+    count: usize,
+) -> anyhow::Result<Vec<Message>> {
+    let mut messages = cached
+        .into_iter()
+        .map(|message| (message.id, message))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut before = None;
+    let mut fetched = 0;
+
+    while messages.len() < count && fetched < count {
+        let limit = (count - fetched).min(PAGE_SIZE);
+        let page = source
+            .fetch_messages(channel_id, before, limit as u8)
+            .await?;
+
+        fetched += page.len();
+        before = page.iter().map(|message| message.id).min();
+
+        let exhausted = page.len() < limit;
+
+        messages.extend(page.into_iter().map(|message| (message.id, message)));
+
+        if exhausted {
+            break;
+        }
+    }
+
+    if fetched > 0 {
+        tracing::debug!("backfilled {fetched} messages of {channel_id}");
+    }
+
+    Ok(messages.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::all::MessageReference;
+    use std::sync::Mutex;
+
+    /// Channel holding messages `1..=len`, recording every request
+    struct FakeHistory {
+        len: u64,
+        requests: Mutex<Vec<(Option<MessageId>, u8)>>,
+    }
+
+    impl FakeHistory {
+        fn new(len: u64) -> Self {
+            Self {
+                len,
+                requests: Mutex::default(),
+            }
+        }
+    }
+
+    impl HistorySource for FakeHistory {
+        async fn fetch_messages(
+            &self,
+            channel_id: ChannelId,
+            before: Option<MessageId>,
+            limit: u8,
+        ) -> anyhow::Result<Vec<Message>> {
+            self.requests.lock().unwrap().push((before, limit));
+
+            let newest = before.map_or(self.len, |before| before.get() - 1);
+
+            Ok((1..=newest)
+                .rev()
+                .take(limit.into())
+                .map(|id| message(channel_id, id))
+                .collect())
+        }
+
+        async fn fetch_message(
+            &self,
+            channel_id: ChannelId,
+            message_id: MessageId,
+        ) -> anyhow::Result<Message> {
+            self.requests.lock().unwrap().push((Some(message_id), 1));
+
+            anyhow::ensure!(message_id.get() <= self.len, "unknown message");
+
+            Ok(message(channel_id, message_id.get()))
+        }
+    }
+
+    fn message(channel_id: ChannelId, id: u64) -> Message {
+        let mut message = Message::default();
+
+        message.id = MessageId::new(id);
+        message.channel_id = channel_id;
+        message.content = format!("message {id}");
+        message
+    }
+
+    fn ids(messages: &[Message]) -> Vec<u64> {
+        messages.iter().map(|message| message.id.get()).collect()
+    }
+
+    #[tokio::test]
+    async fn fills_empty_cache() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(300);
+
+        let messages = backfill(&source, channel_id, Vec::new(), 150)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), (151..=300).collect::<Vec<_>>());
+        assert_eq!(
+            *source.requests.lock().unwrap(),
+            [(None, 100), (Some(MessageId::new(201)), 50)]
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_with_cache() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(10);
+        let cached = vec![message(channel_id, 10), message(channel_id, 9)];
+
+        let messages = backfill(&source, channel_id, cached, 5).await.unwrap();
+
+        assert_eq!(ids(&messages), [6, 7, 8, 9, 10]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_channel_start() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(3);
+
+        let messages = backfill(&source, channel_id, Vec::new(), 50).await.unwrap();
+
+        assert_eq!(ids(&messages), [1, 2, 3]);
+        assert_eq!(source.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backfills_each_channel_once() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(10);
+        let backfill = Backfill::default();
+
+        let messages = backfill
+            .messages(&source, channel_id, vec![message(channel_id, 10)], 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [6, 7, 8, 9, 10]);
+
+        let cached = vec![message(channel_id, 10), message(channel_id, 11)];
+        let messages = backfill
+            .messages(&source, channel_id, cached, 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [6, 7, 8, 9, 10, 11]);
+        assert_eq!(source.requests.lock().unwrap().len(), 1);
+
+        backfill
+            .messages(&source, ChannelId::new(2), Vec::new(), 5)
+            .await
+            .unwrap();
+
+        assert_eq!(source.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn forgets_deleted_messages() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(10);
+        let backfill = Backfill::default();
+
+        let messages = backfill
+            .messages(&source, channel_id, vec![message(channel_id, 10)], 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [6, 7, 8, 9, 10]);
+
+        // 7 deleted through the API, 10 dropped from the cache as well
+        backfill.forget(channel_id, &[MessageId::new(7)]).await;
+
+        let messages = backfill
+            .messages(&source, channel_id, Vec::new(), 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [6, 8, 9]);
+
+        // the cache holds its own, newer copies of these
+        let mut edited = message(channel_id, 9);
+
+        edited.content = "edited".into();
+
+        let messages = backfill
+            .messages(
+                &source,
+                channel_id,
+                vec![edited, message(channel_id, 11)],
+                5,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [6, 8, 9, 11]);
+        assert_eq!(messages[2].content, "edited");
+
+        // nothing older is needed once the cache is full
+        let cached = (12..=16).map(|id| message(channel_id, id)).collect();
+        let messages = backfill
+            .messages(&source, channel_id, cached, 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [12, 13, 14, 15, 16]);
+
+        let messages = backfill
+            .messages(&source, channel_id, vec![message(channel_id, 16)], 5)
+            .await
+            .unwrap();
+
+        assert_eq!(ids(&messages), [16]);
+        assert_eq!(source.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_full_cache() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(10);
+        let cached = (1..=10).map(|id| message(channel_id, id)).collect();
+
+        let messages = backfill(&source, channel_id, cached, 10).await.unwrap();
+
+        assert_eq!(messages.len(), 10);
+        assert!(source.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolves_missing_reply() {
+        let channel_id = ChannelId::new(1);
+        let source = FakeHistory::new(10);
+        let mut reply = message(channel_id, 11);
+
+        reply.message_reference = Some(MessageReference::from((channel_id, MessageId::new(3))));
+
+        resolve_reply(&source, &mut reply).await.unwrap();
+
+        assert_eq!(reply.referenced_message.as_ref().unwrap().id, 3);
+
+        // already resolved
+        resolve_reply(&source, &mut reply).await.unwrap();
+
+        assert_eq!(source.requests.lock().unwrap().len(), 1);
+
+        let mut plain = message(channel_id, 12);
+
+        resolve_reply(&source, &mut plain).await.unwrap();
+
+        assert!(plain.referenced_message.is_none());
+
+        let mut cross_channel = message(channel_id, 13);
+
+        cross_channel.message_reference = Some(MessageReference::from((
+            ChannelId::new(2),
+            MessageId::new(3),
+        )));
+
+        resolve_reply(&source, &mut cross_channel).await.unwrap();
+
+        assert!(cross_channel.referenced_message.is_none());
+
+        let mut deleted = message(channel_id, 14);
+
+        deleted.message_reference = Some(MessageReference::from((channel_id, MessageId::new(4))));
+        deleted.flags = Some(MessageFlags::SOURCE_MESSAGE_DELETED);
+
+        resolve_reply(&source, &mut deleted).await.unwrap();
+
+        assert!(deleted.referenced_message.is_none());
+        assert_eq!(source.requests.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,61 @@
+use alloc::sync::Arc;
+use serenity::all::ChannelId;
+use std::collections::HashMap;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Per-channel state, locked per channel so one channel doesn't hold up the others
+pub struct ChannelLocks<T> {
+    channels: Mutex<HashMap<ChannelId, Arc<Mutex<Option<T>>>>>,
+}
+
+impl<T> Default for ChannelLocks<T> {
+    fn default() -> Self {
+        Self {
+            channels: Mutex::default(),
+        }
+    }
+}
+
+impl<T> ChannelLocks<T> {
+    /// Lock the state of `channel_id`, `None` until something is stored
+    pub async fn lock(&self, channel_id: ChannelId) -> OwnedMutexGuard<Option<T>> {
+        let channel = Arc::clone(self.channels.lock().await.entry(channel_id).or_default());
+
+        channel.lock_owned().await
+    }
+
+    /// Like [`lock`](Self::lock), but `None` for channels never locked before
+    pub async fn lock_existing(&self, channel_id: ChannelId) -> Option<OwnedMutexGuard<Option<T>>> {
+        let channel = self.channels.lock().await.get(&channel_id).cloned()?;
+
+        Some(channel.lock_owned().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::time::Duration;
+
+    #[tokio::test]
+    async fn locks_per_channel() {
+        let locks = ChannelLocks::<u32>::default();
+        let mut first = locks.lock(ChannelId::new(1)).await;
+
+        *first = Some(1);
+
+        // other channels aren't held up
+        assert!(locks.lock(ChannelId::new(2)).await.is_none());
+        assert!(locks.lock_existing(ChannelId::new(3)).await.is_none());
+
+        let waited =
+            tokio::time::timeout(Duration::from_millis(50), locks.lock(ChannelId::new(1))).await;
+
+        assert!(waited.is_err());
+
+        drop(first);
+
+        assert_eq!(*locks.lock(ChannelId::new(1)).await, Some(1));
+        assert!(locks.lock_existing(ChannelId::new(1)).await.is_some());
+    }
+}
@@ -1,61 +1,40 @@
-use self::attachment::{Attachment, GeminiAttachment, GeminiUpload};
-use aho_corasick::AhoCorasick;
+use self::action::{Action, ActionContext};
+use self::attachment::{Attachment, GeminiAttachment, GeminiUpload, InlineBudget};
+use self::cache::ContextCache;
+use self::history::Backfill;
+use self::memory::{Memory, MemoryStore};
+use self::summary::Summaries;
 use core::time::Duration;
 use futures_util::StreamExt;
 use google_gemini::{
-    GeminiClient, GeminiMessage, GeminiRequest, GeminiRole, GeminiSafetySetting,
-    GeminiSafetyThreshold, GeminiSystemPart, Part, TextPart,
+    FunctionRegistry, GeminiClient, GeminiError, GeminiFinishReason, GeminiMessage, GeminiRequest,
+    GeminiRole, GeminiSafetySetting, GeminiSafetyThreshold, GeminiSystemPart, Part,
 };
-use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use serenity::all::{ChannelId, CreateAttachment, CreateMessage, Message, RoleId, Settings};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serenity::all::{ChannelId, CreateMessage, GuildId, Message, MessageId, Settings};
 use serenity::async_trait;
 use serenity::prelude::*;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::sync::LazyLock;
+use std::collections::{HashMap, HashSet};
+use time::OffsetDateTime;
 
 extern crate alloc;
 
+mod action;
 mod attachment;
+mod cache;
+mod context;
+mod history;
+mod lock;
+mod memory;
 mod model;
 mod settings;
+mod summary;
+mod trigger;
 mod util;
 
-const CLEO_ID: RoleId = RoleId::new(1317078903348793435);
-
-static SCHEMA: LazyLock<String> = LazyLock::new(|| {
-    let schema = schemars::schema_for!(Vec<Action>);
-    let serialized = serde_json::to_string(&schema).unwrap();
-
-    format!("respond following this json schema: {serialized}")
-});
-
-#[derive(Debug, Deserialize, JsonSchema)]
-pub enum Action {
-    SendMessage {
-        #[serde(default)]
-        referenced_message: Option<model::MessageId>,
-        content: String,
-    },
-    // pin stuff u rly rly like or should remember
-    PinMessage {
-        message_id: model::MessageId,
-    },
-    // only delete if you think its a good idea or mari is testing
-    DeleteMessages {
-        #[serde(default)]
-        message_ids: Vec<model::MessageId>,
-        #[serde(default)]
-        reason: String,
-    },
-}
-
-#[derive(Debug, Deserialize)]
-#[serde_as]
-#[serde(transparent)]
-pub struct GenResponse(#[serde_as(as = "OneOrMany<_, PreferMany>")] pub Vec<Action>);
+/// Generations per reply before the model has to stop calling functions
+const MAX_TOOL_ITERATIONS: usize = 8;
 
 #[derive(Serialize)]
 struct Un<'a> {
@@ -68,28 +47,42 @@ struct Un<'a> {
 struct Claide {
     gemini: GeminiClient,
     seen: Mutex<HashMap<String, GeminiAttachment>>,
+    context_cache: Option<ContextCache>,
+    memory: Option<MemoryStore>,
+    summaries: Option<Summaries>,
+    backfill: Backfill,
     settings: settings::Settings,
     http_client: reqwest::Client,
-    name_matcher: AhoCorasick,
 }
 
 impl Claide {
     fn new(settings: settings::Settings) -> Self {
+        let mut gemini = GeminiClient::new(settings.gemini.api_key.clone())
+            .with_rate_limits(settings.gemini.rate_limit.to_limits());
+
+        if let Some(model) = &settings.gemini.model {
+            gemini = gemini.with_model(model);
+        }
+
+        if let Some(api_version) = &settings.gemini.api_version {
+            gemini = gemini.with_api_version(api_version);
+        }
+
         Self {
-            gemini: GeminiClient::new(settings.gemini.api_key.clone()),
+            gemini,
             seen: Default::default(),
+            context_cache: settings.gemini.cache.map(ContextCache::new),
+            memory: settings.memory.clone().map(MemoryStore::new),
+            summaries: settings.gemini.summary.clone().map(Summaries::new),
+            backfill: Backfill::default(),
             settings,
             http_client: reqwest::Client::new(),
-            name_matcher: AhoCorasick::builder()
-                .ascii_case_insensitive(true)
-                .build(["cleo"])
-                .unwrap(),
         }
     }
 }
 
 impl Claide {
-    async fn process_message(&self, context: Context, message: Message) -> anyhow::Result<()> {
+    async fn process_message(&self, context: Context, mut message: Message) -> anyhow::Result<()> {
         if self
             .settings
             .discord
@@ -109,28 +102,91 @@ impl Claide {
             return Ok(());
         }
 
-        let is_mentioned = message
-            .mentions
-            .iter()
-            .any(|user| user.id == current_user_id)
-            || self.name_matcher.is_match(&message.content)
-            || message.mention_roles.contains(&CLEO_ID)
-            || message.mention_everyone
-            || !message.attachments.is_empty();
+        let guild_id = message.guild_id.map(|guild_id| guild_id.get());
+        let discord = &self.settings.discord;
+
+        let is_dm = guild_id.is_none();
+
+        if is_dm && !discord.is_dm_allowed(message.author.id.get()) {
+            tracing::debug!("ignored direct message by {}", message.author.id);
+
+            return Ok(());
+        }
+
+        if !is_dm && !discord.is_allowed(guild_id, message.channel_id.get()) {
+            tracing::debug!(
+                "ignored message in disallowed channel {}",
+                message.channel_id
+            );
+
+            return Ok(());
+        }
 
-        if !is_mentioned {
+        let channel_settings = discord.channel(message.channel_id.get());
+        let triggers = discord.triggers(guild_id, message.channel_id.get());
+
+        // every direct message is meant for the bot
+        let mut triggered = is_dm || trigger::is_triggered(&triggers, &message, current_user_id);
+
+        // only worth fetching a missing replied-to message if it could be the bot's
+        if !triggered && triggers.replies && message.referenced_message.is_none() {
+            if let Err(error) = history::resolve_reply(&*context.http, &mut message).await {
+                tracing::warn!("failed to fetch replied message: {error:#}");
+            }
+
+            triggered = trigger::is_reply(&message, current_user_id);
+        }
+
+        if !triggered {
             tracing::debug!("ignored non-mention");
 
             return Ok(());
         }
 
+        let mut memories = Vec::new();
+
         let previous_messages = {
-            let Some(cached_messages) = context.cache.channel_messages(message.channel_id) else {
+            let mut messages = context
+                .cache
+                .channel_messages(message.channel_id)
+                .map(|messages| messages.values().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+
+            let history_messages = self.settings.discord.history_messages;
+
+            if history_messages > 0 {
+                match self
+                    .backfill
+                    .messages(
+                        &*context.http,
+                        message.channel_id,
+                        messages.clone(),
+                        history_messages,
+                    )
+                    .await
+                {
+                    Ok(backfilled) => messages = backfilled,
+                    Err(error) => tracing::warn!("failed to backfill history: {error:#}"),
+                }
+            }
+
+            // keep the message being replied to in context, however old it is
+            if let Some(referenced) = message
+                .referenced_message
+                .as_deref()
+                .filter(|referenced| referenced.channel_id == message.channel_id)
+            {
+                if messages.iter().all(|message| message.id != referenced.id) {
+                    messages.push(referenced.clone());
+                }
+            }
+
+            if messages.is_empty() {
                 anyhow::bail!("no channel messages");
-            };
+            }
 
-            let mut messages = cached_messages
-                .values()
+            let mut messages = messages
+                .iter()
                 .filter(|msg| {
                     !self
                         .settings
@@ -140,10 +196,21 @@ impl Claide {
                 })
                 .collect::<Vec<_>>();
 
-            messages.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+            messages.sort_unstable_by_key(|message| message.id);
 
             let mut previous_messages = Vec::with_capacity(messages.len());
             for message in messages {
+                if !message.content.trim().is_empty() {
+                    memories.push(Memory {
+                        message_id: message.id.get(),
+                        channel_id: message.channel_id.get(),
+                        user_id: message.author.id.get(),
+                        name: message.author.display_name().to_string(),
+                        content: message.content.clone(),
+                        embedding: Default::default(),
+                    });
+                }
+
                 let content = match message.kind {
                     serenity::all::MessageType::PinsAdd => "*pinned a message to this channel*",
                     _ => &message.content,
@@ -181,7 +248,7 @@ impl Claide {
                     .map(Attachment)
                     .collect();
 
-                previous_messages.push((role, content, attachments));
+                previous_messages.push((message.id.get(), role, content, attachments));
             }
 
             previous_messages
@@ -194,14 +261,14 @@ impl Claide {
             .get_or_insert_default()
             .parts
             .push(GeminiSystemPart {
-                text: format!("{}\n{}", self.settings.gemini.personality, *SCHEMA),
+                text: channel_settings
+                    .and_then(|channel| channel.personality.clone())
+                    .unwrap_or_else(|| self.settings.gemini.personality.clone()),
             });
 
-        request
-            .generation_config
-            .get_or_insert_default()
-            .response_mime_type
-            .push_str("application/json");
+        request.model = channel_settings.and_then(|channel| channel.model.clone());
+
+        request.generation_config = Some(self.settings.gemini.generation.to_config());
 
         let settings = [
             GeminiSafetySetting::HarmCategoryHarassment,
@@ -215,20 +282,35 @@ impl Claide {
 
         request.safety_settings.extend(settings);
 
-        for (role, text, attachments) in previous_messages {
+        let inline_budget = &InlineBudget::new(self.settings.gemini.inline_request_limit);
+
+        // IDs of the messages in `request.contents`, to tell which were dropped
+        let mut message_ids = Vec::with_capacity(previous_messages.len());
+
+        for (message_id, role, text, attachments) in previous_messages {
             let attachment = attachments.into_iter().map(|attachment| async move {
-                anyhow::Ok(
-                    match self.seen.lock().await.entry(attachment.url().to_string()) {
-                        Entry::Occupied(occupied) => occupied.get().clone(),
-                        Entry::Vacant(vacant) => {
-                            let pair = attachment.upload_into_gemini(self).await?;
-
-                            vacant.insert(pair.clone());
-
-                            pair
-                        }
-                    },
-                )
+                // not held while uploading, that would stall every other lookup
+                let cached = self.seen.lock().await.get(attachment.url()).cloned();
+
+                if let Some(uploaded) = cached {
+                    if !uploaded.is_expired_at(OffsetDateTime::now_utc()) {
+                        return anyhow::Ok(uploaded);
+                    }
+
+                    tracing::info!("re-uploading expired attachment: {}", attachment.url());
+                }
+
+                let uploaded = attachment.upload_into_gemini(self, inline_budget).await?;
+
+                // inline data is fetched again when needed instead of piling up here
+                if let GeminiAttachment::File { .. } = uploaded {
+                    self.seen
+                        .lock()
+                        .await
+                        .insert(attachment.url().to_string(), uploaded.clone());
+                }
+
+                anyhow::Ok(uploaded)
             });
 
             let iter = futures_util::stream::iter(attachment)
@@ -236,7 +318,11 @@ impl Claide {
                 .collect::<Vec<_>>()
                 .await
                 .into_iter()
-                .flatten()
+                .filter_map(|attachment| {
+                    attachment
+                        .inspect_err(|error| tracing::warn!("skipping attachment: {error:#}"))
+                        .ok()
+                })
                 .map(Part::from);
 
             let mut parts = vec![Part::from(text.to_string())];
@@ -244,148 +330,254 @@ impl Claide {
             parts.extend(iter);
 
             request.contents.push(GeminiMessage::new(role, parts));
+            message_ids.push(message_id);
         }
 
         if request.contents.is_empty() {
             anyhow::bail!("request is empty");
         }
 
-        tracing::debug!("send request: {request:#?}");
+        let actions = ActionContext {
+            context: &context,
+            channel_id: message.channel_id,
+        };
 
-        let response = self.gemini.generate(request).await;
+        let mut registry = FunctionRegistry::new();
 
-        let text = match response.as_deref() {
-            Ok(
-                [Part::Text(TextPart {
-                    text,
-                    thought: false,
-                })],
-            ) => text,
-            Ok(_parts) => {
-                let mut builder = CreateMessage::new();
-                builder = builder.content("```\nissue```\n-# repor issue to mari".to_string());
+        let allowed_actions = channel_settings
+            .and_then(|channel| channel.actions.as_deref())
+            .unwrap_or(&Action::ALL);
 
-                message.channel_id.send_message(&context, builder).await?;
+        actions.register(
+            &mut registry,
+            allowed_actions
+                .iter()
+                .copied()
+                .filter(|action| !is_dm || !action.is_guild_only()),
+        );
 
-                return Ok(());
+        // only here to be counted and cached along with the request
+        if !registry.is_empty() {
+            request.tools.push(registry.tool());
+        }
+
+        // recalled before fitting, but only added after caching so they never end up in a
+        // cached prefix
+        let recalled = match (&self.memory, message.guild_id) {
+            (Some(store), Some(guild_id)) => {
+                self.recall_memories(store, guild_id.get(), &message, memories)
+                    .await
             }
-            Err(error) => {
-                let mut builder = CreateMessage::new();
-                builder = builder.content(format!("```\n{error}```\n-# repor issue to mari"));
+            _ => None,
+        };
 
-                message.channel_id.send_message(&context, builder).await?;
+        if let Some(budget) = self.settings.gemini.context_token_budget {
+            let referenced_id = message
+                .referenced_message
+                .as_ref()
+                .map(|referenced| referenced.id.get());
+            let pinned = message_ids
+                .iter()
+                .position(|message_id| Some(*message_id) == referenced_id);
+
+            // room for what is only added after fitting
+            let reserved = recalled.as_ref().map_or(0, context::estimate)
+                + self.summaries.as_ref().map_or(0, Summaries::max_tokens);
+
+            let dropped = context::fit_to_budget(
+                &self.gemini,
+                &mut request,
+                budget.saturating_sub(reserved),
+                pinned,
+            )
+            .await
+            .unwrap_or_else(|error| {
+                tracing::warn!("failed to fit context into {budget} tokens: {error}");
+
+                Vec::new()
+            });
 
-                return Ok(());
+            // part of the system instruction, so a new summary also rebuilds the context cache
+            if let Some(summaries) = &self.summaries {
+                // the pinned message is kept, the rest were cut oldest first
+                let dropped = message_ids
+                    .into_iter()
+                    .filter(|message_id| Some(*message_id) != referenced_id)
+                    .zip(dropped)
+                    .collect::<Vec<_>>();
+                let summary = summaries
+                    .update(&self.gemini, message.channel_id, &dropped)
+                    .await;
+
+                if !summary.is_empty() {
+                    request
+                        .system_instruction
+                        .get_or_insert_default()
+                        .parts
+                        .push(summary::system_part(&summary));
+                }
             }
-        };
+        }
 
-        let result = serde_json::from_str::<GenResponse>(text)
-            .map(|resp| resp.0)
-            .or_else(|_error| serde_json::from_str::<Action>(text).map(|value| vec![value]));
+        let mut cached = false;
 
-        let actions = match result {
-            Ok(content) => content,
-            Err(error) => anyhow::bail!("invalid response: {error}"),
-        };
+        if let Some(cache) = &self.context_cache {
+            cached = cache
+                .apply(&self.gemini, message.channel_id, &mut request)
+                .await;
+        }
 
-        for action in actions {
-            match action {
-                Action::SendMessage {
-                    referenced_message,
-                    content,
-                } => {
-                    let mut builder = CreateMessage::new();
+        // generate_with_tools attaches the registry's tool itself
+        request.tools.clear();
 
-                    if content.chars().count() > 1950 {
-                        builder = builder.add_file(CreateAttachment::bytes(content, "message.txt"));
-                    } else {
-                        builder = builder.content(content);
-                    }
+        if let Some(recalled) = recalled {
+            let index = request.contents.len().saturating_sub(1);
 
-                    if let Some(message_id) = referenced_message {
-                        if let Some(messages) = context.cache.channel_messages(message.channel_id) {
-                            if let Some(message) = messages.get(&message_id.into()) {
-                                builder = builder.reference_message(message);
-                            }
-                        }
-                    }
+            request.contents.insert(index, recalled);
+        }
+
+        tracing::debug!("send request: {request:#?}");
 
-                    if let Err(error) = message.channel_id.send_message(&context, builder).await {
-                        tracing::error!("failed to send message: {error}");
+        tracing::debug!("gemini queue: {:?}", self.gemini.rate_limiter_metrics());
+
+        let response = self
+            .gemini
+            .generate_with_tools(request, &registry, MAX_TOOL_ITERATIONS)
+            .await;
+
+        let response = match response {
+            Ok(response) => response,
+            Err(error) => {
+                if let Some(cache) = self.context_cache.as_ref() {
+                    if cached && cache::is_cache_error(&error) {
+                        cache.invalidate(&self.gemini, message.channel_id).await;
                     }
                 }
-                Action::PinMessage { message_id } => {
-                    let Some(msg) = context
-                        .cache
-                        .channel_messages(message.channel_id)
-                        .and_then(|cache| cache.get(&message_id.into()).cloned())
-                    else {
-                        continue;
-                    };
-
-                    if let Err(error) = msg.pin(&context).await {
-                        tracing::error!("failed to send message: {error}");
+
+                let content = match error {
+                    GeminiError::Blocked { reason } => {
+                        format!("-# cant answer that, prompt blocked ({reason})")
                     }
-                }
-                Action::DeleteMessages {
-                    message_ids,
-                    reason,
-                } => {
-                    if let Err(error) = delete_messages(
-                        message.channel_id,
-                        &context,
-                        message_ids.into_iter().map(Into::into),
-                        &reason,
-                    )
-                    .await
+                    error if error.status() == Some(StatusCode::TOO_MANY_REQUESTS) => {
+                        "-# too many requests rn, try again in a bit".into()
+                    }
+                    error
+                        if error
+                            .status()
+                            .is_some_and(|status| status.is_server_error()) =>
                     {
-                        tracing::error!("failed to send message: {error}");
+                        "-# gemini is having a moment, try again in a bit".into()
                     }
-                }
+                    error => format!("```\n{error}```\n-# repor issue to mari"),
+                };
+
+                let mut builder = CreateMessage::new();
+                builder = builder.content(content);
+
+                message.channel_id.send_message(&context, builder).await?;
+
+                return Ok(());
             }
+        };
+
+        if response.truncated {
+            tracing::warn!("stopped after {MAX_TOOL_ITERATIONS} tool iterations");
+        }
+
+        let text = response.response.text();
+
+        if let Some(text) = action::relayed_text(allowed_actions, &response.calls, &text) {
+            let args = serde_json::json!({ "content": text });
+
+            actions.execute(Action::SendMessage, args).await;
+        } else if response.calls.is_empty() && text.trim().is_empty() {
+            let content = match response.response.finish_reason {
+                Some(GeminiFinishReason::Safety | GeminiFinishReason::ProhibitedContent) => {
+                    "-# cant answer that, response blocked for safety".into()
+                }
+                Some(GeminiFinishReason::Recitation) => {
+                    "-# cant answer that, it would recite copyrighted stuff".into()
+                }
+                Some(GeminiFinishReason::MaxTokens) => {
+                    "-# ran out of tokens before saying anything".into()
+                }
+                finish_reason => {
+                    format!("```\nissue ({finish_reason:?})```\n-# repor issue to mari")
+                }
+            };
+
+            let mut builder = CreateMessage::new();
+            builder = builder.content(content);
+
+            message.channel_id.send_message(&context, builder).await?;
         }
 
         Ok(())
     }
 }
 
-async fn delete_messages(
-    channel_id: ChannelId,
-    context: &Context,
-    message_ids: impl IntoIterator<Item = serenity::model::id::MessageId>,
-    reason: &str,
-) -> anyhow::Result<()> {
-    let mut target_message_ids = Vec::new();
-
-    if let Some(messages) = context.cache.channel_messages(channel_id) {
-        for message_id in message_ids {
-            if messages.contains_key(&message_id) {
-                target_message_ids.push(message_id);
-            }
+impl Claide {
+    /// Remember the channel's messages and recall relevant older ones as a message
+    async fn recall_memories(
+        &self,
+        store: &MemoryStore,
+        guild_id: u64,
+        message: &Message,
+        memories: Vec<Memory>,
+    ) -> Option<GeminiMessage> {
+        let in_context = memories
+            .iter()
+            .map(|memory| memory.message_id)
+            .collect::<HashSet<_>>();
+
+        if let Err(error) = store.remember(&self.gemini, guild_id, memories).await {
+            tracing::warn!("failed to remember messages: {error:#}");
         }
-    }
 
-    for message_ids in target_message_ids.chunks(100) {
-        match message_ids {
-            [] => continue,
-            [message_id] => {
-                context
-                    .http
-                    .delete_message(channel_id, *message_id, Some(reason))
-                    .await?;
-            }
-            message_ids => {
-                let map = serde_json::json!({ "messages": message_ids });
+        let mut recalled = match store
+            .recall(
+                &self.gemini,
+                guild_id,
+                message.channel_id.get(),
+                &message.content,
+                &in_context,
+            )
+            .await
+        {
+            Ok(recalled) => recalled,
+            Err(error) => {
+                tracing::warn!("failed to recall messages: {error:#}");
 
-                context
-                    .http
-                    .delete_messages(channel_id, &map, Some(reason))
-                    .await?;
+                return None;
             }
+        };
+
+        let blacklisted_users = &self.settings.discord.blacklisted_users;
+
+        recalled.retain(|memory| !blacklisted_users.contains(&memory.user_id));
+
+        if recalled.is_empty() {
+            return None;
         }
-    }
 
-    Ok(())
+        tracing::debug!("recalled {} older messages", recalled.len());
+
+        let mut text = String::from("older messages that might be relevant:");
+
+        for memory in &recalled {
+            let un = Un {
+                name: &memory.name,
+                content: &memory.content,
+                message_id: memory.message_id,
+                user_id: memory.user_id,
+            };
+
+            text.push('\n');
+            text.push_str(&serde_json::to_string(&un).unwrap_or_default());
+        }
+
+        Some(GeminiMessage::new(GeminiRole::User, vec![Part::from(text)]))
+    }
 }
 
 #[async_trait]
@@ -395,6 +587,26 @@ impl EventHandler for Claide {
             tracing::error!("process_message: {error:?}");
         }
     }
+
+    async fn message_delete(
+        &self,
+        _context: Context,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.backfill.forget(channel_id, &[message_id]).await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _context: Context,
+        channel_id: ChannelId,
+        message_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        self.backfill.forget(channel_id, &message_ids).await;
+    }
 }
 
 #[tokio::main]
@@ -408,13 +620,16 @@ async fn main() -> anyhow::Result<()> {
     cache_settings.max_messages = 500;
     cache_settings.time_to_live = Duration::from_secs(24 * 60 * 60);
 
-    let mut client = Client::builder(
-        settings.discord.token.clone(),
-        GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILD_MESSAGES,
-    )
-    .cache_settings(cache_settings)
-    .event_handler(Claide::new(settings))
-    .await?;
+    let mut intents = GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILD_MESSAGES;
+
+    if settings.discord.direct_messages.is_some() {
+        intents |= GatewayIntents::DIRECT_MESSAGES;
+    }
+
+    let mut client = Client::builder(settings.discord.token.clone(), intents)
+        .cache_settings(cache_settings)
+        .event_handler(Claide::new(settings))
+        .await?;
 
     client.start().await?;
 
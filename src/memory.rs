@@ -0,0 +1,407 @@
+use crate::settings::MemorySettings;
+use anyhow::Context as _;
+use google_gemini::{GeminiClient, GeminiEmbedding, GeminiTaskType};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Anything that can turn text into embeddings
+pub trait Embedder {
+    /// Embed texts to be searched later
+    async fn embed_documents(&self, texts: &[&str]) -> anyhow::Result<Vec<GeminiEmbedding>>;
+
+    /// Embed a text to search with
+    async fn embed_query(&self, text: &str) -> anyhow::Result<GeminiEmbedding>;
+}
+
+impl Embedder for GeminiClient {
+    async fn embed_documents(&self, texts: &[&str]) -> anyhow::Result<Vec<GeminiEmbedding>> {
+        Ok(self
+            .embed_texts(texts, GeminiTaskType::RetrievalDocument)
+            .await?)
+    }
+
+    async fn embed_query(&self, text: &str) -> anyhow::Result<GeminiEmbedding> {
+        let mut embeddings = self
+            .embed_texts(&[text], GeminiTaskType::RetrievalQuery)
+            .await?;
+
+        embeddings.pop().context("no embedding returned")
+    }
+}
+
+/// Discord message remembered with its embedding
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Memory {
+    pub message_id: u64,
+    pub channel_id: u64,
+    pub user_id: u64,
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub embedding: GeminiEmbedding,
+}
+
+/// Per-guild messages and embeddings, persisted as one JSON line per message
+pub struct MemoryStore {
+    settings: MemorySettings,
+    guilds: Mutex<HashMap<u64, GuildMemory>>,
+}
+
+#[derive(Default)]
+struct GuildMemory {
+    ids: HashSet<u64>,
+    memories: Vec<Memory>,
+}
+
+impl MemoryStore {
+    pub fn new(settings: MemorySettings) -> Self {
+        Self {
+            settings,
+            guilds: Mutex::default(),
+        }
+    }
+
+    fn path(&self, guild_id: u64) -> PathBuf {
+        self.settings.path.join(format!("{guild_id}.jsonl"))
+    }
+
+    /// Run `f` on the memory of `guild_id`, loading it from disk first if needed
+    async fn with_guild<T>(
+        &self,
+        guild_id: u64,
+        f: impl FnOnce(&mut GuildMemory) -> T,
+    ) -> anyhow::Result<T> {
+        let mut guilds = self.guilds.lock().await;
+
+        Ok(f(self.guild(&mut guilds, guild_id).await?))
+    }
+
+    async fn guild<'a>(
+        &self,
+        guilds: &'a mut HashMap<u64, GuildMemory>,
+        guild_id: u64,
+    ) -> anyhow::Result<&'a mut GuildMemory> {
+        let vacant = match guilds.entry(guild_id) {
+            Entry::Occupied(occupied) => return Ok(occupied.into_mut()),
+            Entry::Vacant(vacant) => vacant,
+        };
+
+        let contents = match fs::read_to_string(self.path(guild_id)).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut guild = GuildMemory::default();
+
+        // a crash mid-append leaves a torn line, which shouldn't cost the rest
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            match serde_json::from_str::<Memory>(line) {
+                Ok(memory) if guild.ids.insert(memory.message_id) => guild.memories.push(memory),
+                Ok(_) => {}
+                Err(error) => {
+                    tracing::warn!("skipping unreadable memory of guild {guild_id}: {error}");
+                }
+            }
+        }
+
+        Ok(vacant.insert(guild))
+    }
+
+    /// Embed and store the messages not remembered yet, returns how many were new
+    pub async fn remember(
+        &self,
+        embedder: &impl Embedder,
+        guild_id: u64,
+        mut memories: Vec<Memory>,
+    ) -> anyhow::Result<usize> {
+        memories.retain(|memory| !memory.content.trim().is_empty());
+
+        let memories = self
+            .with_guild(guild_id, |guild| {
+                memories.retain(|memory| !guild.ids.contains(&memory.message_id));
+                memories
+            })
+            .await?;
+
+        if memories.is_empty() {
+            return Ok(0);
+        }
+
+        let texts = memories
+            .iter()
+            .map(|memory| memory.content.as_str())
+            .collect::<Vec<_>>();
+
+        let embeddings = embedder.embed_documents(&texts).await?;
+
+        anyhow::ensure!(
+            embeddings.len() == memories.len(),
+            "expected {} embeddings, got {}",
+            memories.len(),
+            embeddings.len()
+        );
+
+        let memories = memories
+            .into_iter()
+            .zip(embeddings)
+            .map(|(memory, embedding)| Memory {
+                embedding,
+                ..memory
+            })
+            .collect::<Vec<_>>();
+
+        // held while appending, so nothing is marked as known before it is on disk
+        let mut guilds = self.guilds.lock().await;
+        let guild = self.guild(&mut guilds, guild_id).await?;
+
+        // another mention may have stored some of them while we were embedding
+        let memories = memories
+            .into_iter()
+            .filter(|memory| !guild.ids.contains(&memory.message_id))
+            .collect::<Vec<_>>();
+
+        let mut lines = Vec::new();
+
+        for memory in &memories {
+            serde_json::to_writer(&mut lines, memory)?;
+            lines.push(b'\n');
+        }
+
+        fs::create_dir_all(&self.settings.path).await?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(guild_id))
+            .await?;
+
+        file.write_all(&lines).await?;
+        // tokio writes in the background, errors only show up once flushed
+        file.flush().await?;
+
+        guild
+            .ids
+            .extend(memories.iter().map(|memory| memory.message_id));
+        guild.memories.extend(memories.iter().cloned());
+
+        Ok(memories.len())
+    }
+
+    /// Stored messages of `channel_id` most similar to `query`, best first, skipping `exclude`
+    ///
+    /// Other channels are left out, they may be private to people who can't see this one.
+    pub async fn recall(
+        &self,
+        embedder: &impl Embedder,
+        guild_id: u64,
+        channel_id: u64,
+        query: &str,
+        exclude: &HashSet<u64>,
+    ) -> anyhow::Result<Vec<Memory>> {
+        if query.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let query = embedder.embed_query(query).await?;
+
+        self.with_guild(guild_id, |guild| {
+            let mut scored = guild
+                .memories
+                .iter()
+                .filter(|memory| {
+                    memory.channel_id == channel_id && !exclude.contains(&memory.message_id)
+                })
+                .map(|memory| (query.cosine_similarity(&memory.embedding), memory))
+                .filter(|(score, _)| *score >= self.settings.min_score)
+                .collect::<Vec<_>>();
+
+            scored.sort_unstable_by(|(a, _), (b, _)| b.total_cmp(a));
+
+            scored
+                .into_iter()
+                .take(self.settings.top_k)
+                .map(|(_, memory)| memory.clone())
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bag of words, each word hashed (FNV-1a) into one of 256 dimensions
+    struct WordEmbedder;
+
+    impl WordEmbedder {
+        fn embed(text: &str) -> GeminiEmbedding {
+            let mut values = vec![0.0; 256];
+
+            for word in text.split_whitespace() {
+                let hash = word
+                    .to_lowercase()
+                    .bytes()
+                    .fold(0xcbf29ce484222325, |hash, byte| {
+                        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+                    });
+
+                values[(hash % 256) as usize] += 1.0;
+            }
+
+            GeminiEmbedding { values }
+        }
+    }
+
+    impl Embedder for WordEmbedder {
+        async fn embed_documents(&self, texts: &[&str]) -> anyhow::Result<Vec<GeminiEmbedding>> {
+            Ok(texts.iter().map(|text| Self::embed(text)).collect())
+        }
+
+        async fn embed_query(&self, text: &str) -> anyhow::Result<GeminiEmbedding> {
+            Ok(Self::embed(text))
+        }
+    }
+
+    fn store(name: &str) -> MemoryStore {
+        let path =
+            std::env::temp_dir().join(format!("claide-memory-{name}-{}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        MemoryStore::new(MemorySettings {
+            path,
+            top_k: 2,
+            min_score: 0.3,
+        })
+    }
+
+    fn memory(message_id: u64, content: &str) -> Memory {
+        Memory {
+            message_id,
+            channel_id: 1,
+            user_id: 2,
+            name: "mari".into(),
+            content: content.into(),
+            embedding: GeminiEmbedding::default(),
+        }
+    }
+
+    fn contents(memories: &[Memory]) -> Vec<&str> {
+        memories
+            .iter()
+            .map(|memory| memory.content.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recalls_similar_messages() {
+        let store = store("recall");
+        let messages = vec![
+            memory(1, "my cat is orange"),
+            memory(2, "pizza with extra cheese"),
+            memory(3, "the orange cat sleeps all day"),
+            memory(4, ""),
+        ];
+
+        assert_eq!(
+            store
+                .remember(&WordEmbedder, 7, messages.clone())
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(store.remember(&WordEmbedder, 7, messages).await.unwrap(), 0);
+
+        let recalled = store
+            .recall(&WordEmbedder, 7, 1, "orange cat", &HashSet::new())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            contents(&recalled),
+            ["my cat is orange", "the orange cat sleeps all day"]
+        );
+
+        let recalled = store
+            .recall(&WordEmbedder, 7, 1, "orange cat", &HashSet::from([1]))
+            .await
+            .unwrap();
+
+        assert_eq!(contents(&recalled), ["the orange cat sleeps all day"]);
+
+        let other_guild = store
+            .recall(&WordEmbedder, 8, 1, "orange cat", &HashSet::new())
+            .await
+            .unwrap();
+
+        assert!(other_guild.is_empty());
+
+        let other_channel = store
+            .recall(&WordEmbedder, 7, 2, "orange cat", &HashSet::new())
+            .await
+            .unwrap();
+
+        assert!(other_channel.is_empty());
+    }
+
+    #[tokio::test]
+    async fn persists_across_restarts() {
+        let store = store("persist");
+
+        store
+            .remember(&WordEmbedder, 7, vec![memory(1, "pizza with extra cheese")])
+            .await
+            .unwrap();
+
+        let restarted = MemoryStore::new(store.settings.clone());
+        let recalled = restarted
+            .recall(&WordEmbedder, 7, 1, "cheese pizza", &HashSet::new())
+            .await
+            .unwrap();
+
+        assert_eq!(contents(&recalled), ["pizza with extra cheese"]);
+
+        std::fs::remove_dir_all(&store.settings.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_torn_lines() {
+        let store = store("torn");
+
+        store
+            .remember(&WordEmbedder, 7, vec![memory(1, "pizza with extra cheese")])
+            .await
+            .unwrap();
+
+        // as left behind by a crash halfway through an append
+        let path = store.path(7);
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+
+        contents.push_str("{\"message_id\":2,\"chan\n");
+        std::fs::write(&path, contents).unwrap();
+
+        let restarted = MemoryStore::new(store.settings.clone());
+
+        restarted
+            .remember(&WordEmbedder, 7, vec![memory(3, "cheese on toast")])
+            .await
+            .unwrap();
+
+        let recalled = restarted
+            .recall(&WordEmbedder, 7, 1, "cheese", &HashSet::new())
+            .await
+            .unwrap();
+
+        assert_eq!(recalled.len(), 2);
+
+        std::fs::remove_dir_all(&store.settings.path).unwrap();
+    }
+}
@@ -2,12 +2,15 @@ use alloc::borrow::Cow;
 use core::fmt;
 use core::num::NonZero;
 use schemars::gen::SchemaGenerator;
-use schemars::schema::Schema;
+use schemars::schema::{InstanceType, Metadata, Schema, SchemaObject};
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
 use serenity::model::id;
 
-#[derive(Clone, Copy, Deserialize, Serialize)]
+/// Message ID as the model passes it, a string since function arguments lose
+/// integer precision beyond 2^53
+#[derive(Clone, Copy, Serialize)]
 #[serde(transparent)]
 pub struct MessageId(id::MessageId);
 
@@ -34,8 +37,43 @@ impl JsonSchema for MessageId {
         Cow::Borrowed(stringify!(MessageId))
     }
 
-    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
-        <NonZero<u64> as JsonSchema>::json_schema(generator)
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            metadata: Some(Box::new(Metadata {
+                description: Some("message ID as a string of digits".into()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Integer(u64),
+            String(String),
+            Float(f64),
+        }
+
+        let message_id = match Raw::deserialize(deserializer)? {
+            Raw::Integer(message_id) => message_id,
+            Raw::String(message_id) => message_id.trim().parse().map_err(D::Error::custom)?,
+            Raw::Float(message_id) => {
+                return Err(D::Error::custom(format_args!(
+                    "message IDs must be passed as strings, {message_id} lost precision"
+                )))
+            }
+        };
+
+        Self::new(message_id).ok_or_else(|| D::Error::custom("message ID can't be 0"))
     }
 }
 
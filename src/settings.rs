@@ -1,12 +1,14 @@
+use crate::action::Action;
 use aho_corasick::{AhoCorasick, BuildError};
 use alloc::borrow::Cow;
 use core::fmt::Display;
 use figment::providers::{Format, Toml};
 use figment::Figment;
+use google_gemini::{GeminiGenerationConfig, GeminiThinkingConfig, RateLimits};
 use reqwest::Url;
 use serde::de::Error;
 use serde::{de, Deserialize, Deserializer};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 
@@ -72,11 +74,251 @@ impl<'de> Deserialize<'de> for DomainMatcher {
     }
 }
 
+/// Finds any of a list of names in a message, case-insensitively
+///
+/// Compiled once when the settings are loaded, clones share the automaton.
+#[derive(Clone, Debug)]
+pub struct NameMatcher {
+    names: Vec<String>,
+    backend: AhoCorasick,
+}
+
+impl NameMatcher {
+    pub fn new<I, P>(names: I) -> Result<Self, BuildError>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        let names = names.into_iter().map(Into::into).collect::<Vec<_>>();
+
+        Ok(Self {
+            backend: AhoCorasick::builder()
+                .ascii_case_insensitive(true)
+                .build(&names)?,
+            names,
+        })
+    }
+
+    /// Whether `content` mentions any of the names, never with no names
+    pub fn is_match(&self, content: &str) -> bool {
+        self.backend.is_match(content)
+    }
+}
+
+impl PartialEq for NameMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.names == other.names
+    }
+}
+
+impl Default for NameMatcher {
+    fn default() -> Self {
+        Self::new(Vec::<String>::new()).expect("empty name list")
+    }
+}
+
+impl<'de> Deserialize<'de> for NameMatcher {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+
+        Self::new(names).map_err(de::Error::custom)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct DiscordSettings {
     pub token: String,
     #[serde(default)]
     pub blacklisted_users: HashSet<u64>,
+    /// Fetch this many recent messages when fewer are cached, e.g. after a restart
+    #[serde(default = "default_history_messages")]
+    pub history_messages: usize,
+    #[serde(default)]
+    pub triggers: TriggerSettings,
+    /// Only these guilds are answered in, all of them when empty
+    #[serde(default)]
+    pub allowed_guilds: HashSet<u64>,
+    #[serde(default)]
+    pub denied_guilds: HashSet<u64>,
+    /// Only these channels are answered in, all of them when empty
+    #[serde(default)]
+    pub allowed_channels: HashSet<u64>,
+    #[serde(default)]
+    pub denied_channels: HashSet<u64>,
+    /// Keyed by channel ID, TOML keys are always strings
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelSettings>,
+    /// Answer direct messages, off when absent
+    #[serde(default)]
+    pub direct_messages: Option<DirectMessageSettings>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DirectMessageSettings {
+    /// Only DMs from these user IDs are answered
+    pub allowed_users: HashSet<u64>,
+}
+
+impl DiscordSettings {
+    /// Whether the bot may answer in a channel, denials win over allowances
+    pub fn is_allowed(&self, guild_id: Option<u64>, channel_id: u64) -> bool {
+        let guild_allowed = guild_id.is_none_or(|guild_id| {
+            !self.denied_guilds.contains(&guild_id)
+                && (self.allowed_guilds.is_empty() || self.allowed_guilds.contains(&guild_id))
+        });
+
+        guild_allowed
+            && !self.denied_channels.contains(&channel_id)
+            && (self.allowed_channels.is_empty() || self.allowed_channels.contains(&channel_id))
+    }
+
+    /// Whether the bot may answer direct messages from a user
+    pub fn is_dm_allowed(&self, user_id: u64) -> bool {
+        self.direct_messages
+            .as_ref()
+            .is_some_and(|direct_messages| direct_messages.allowed_users.contains(&user_id))
+    }
+
+    pub fn channel(&self, channel_id: u64) -> Option<&ChannelSettings> {
+        self.channels.get(&channel_id.to_string())
+    }
+
+    /// Triggers in effect for a channel, channel overrides win over guild ones
+    pub fn triggers(&self, guild_id: Option<u64>, channel_id: u64) -> Triggers {
+        let mut triggers = self.triggers.triggers.clone();
+
+        if let Some(overrides) =
+            guild_id.and_then(|guild_id| self.triggers.guilds.get(&guild_id.to_string()))
+        {
+            overrides.apply(&mut triggers);
+        }
+
+        if let Some(channel) = self.channel(channel_id) {
+            channel.triggers.apply(&mut triggers);
+        }
+
+        triggers
+    }
+}
+
+/// Overrides for a single channel, anything unset is inherited
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    /// Path to a personality text file used instead of the default one
+    #[serde(deserialize_with = "deserialize_optional_personality")]
+    pub personality: Option<String>,
+    pub model: Option<String>,
+    pub triggers: TriggerOverrides,
+    /// Actions the model may use, all of them when unset
+    pub actions: Option<Vec<Action>>,
+}
+
+const fn default_history_messages() -> usize {
+    50
+}
+
+/// What makes the bot answer a message, besides mentioning it directly
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Triggers {
+    /// Matched case-insensitively anywhere in a message
+    pub names: NameMatcher,
+    pub roles: Vec<u64>,
+    pub everyone: bool,
+    pub attachments: AttachmentTrigger,
+    /// Replies to the bot's messages
+    pub replies: bool,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self {
+            names: NameMatcher::default(),
+            roles: Vec::new(),
+            everyone: true,
+            attachments: AttachmentTrigger::Off,
+            replies: true,
+        }
+    }
+}
+
+/// When a message with attachments is answered without any other trigger
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "policy")]
+pub enum AttachmentTrigger {
+    #[default]
+    Off,
+    Always,
+    /// Only in these channel IDs
+    Channels {
+        channels: Vec<u64>,
+    },
+    /// Only if the message also mentions one of these names, which needn't
+    /// trigger the bot on their own
+    WithName {
+        names: NameMatcher,
+    },
+    /// Randomly, this fraction of such messages
+    Probability {
+        probability: f64,
+    },
+}
+
+/// [`Triggers`] of a guild or channel, anything unset is inherited
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TriggerOverrides {
+    pub names: Option<NameMatcher>,
+    pub roles: Option<Vec<u64>>,
+    pub everyone: Option<bool>,
+    pub attachments: Option<AttachmentTrigger>,
+    pub replies: Option<bool>,
+}
+
+impl TriggerOverrides {
+    fn apply(&self, triggers: &mut Triggers) {
+        if let Some(names) = &self.names {
+            triggers.names.clone_from(names);
+        }
+
+        if let Some(roles) = &self.roles {
+            triggers.roles.clone_from(roles);
+        }
+
+        triggers.everyone = self.everyone.unwrap_or(triggers.everyone);
+        if let Some(attachments) = &self.attachments {
+            triggers.attachments.clone_from(attachments);
+        }
+
+        triggers.replies = self.replies.unwrap_or(triggers.replies);
+    }
+}
+
+/// Default triggers, channel overrides live in [`ChannelSettings`]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TriggerSettings {
+    #[serde(flatten)]
+    pub triggers: Triggers,
+    /// Keyed by guild ID
+    pub guilds: HashMap<String, TriggerOverrides>,
+    /// Former home of channel overrides, rejected so they aren't silently ignored
+    #[serde(deserialize_with = "deserialize_moved_channels")]
+    channels: (),
+}
+
+fn deserialize_moved_channels<'de, D>(_deserializer: D) -> Result<(), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Err(Error::custom(
+        "channel trigger overrides moved from [discord.triggers.channels.<id>] \
+         to [discord.channels.<id>.triggers]",
+    ))
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -85,12 +327,182 @@ pub struct GeminiSettings {
     #[serde(default, deserialize_with = "deserialize_personality")]
     pub personality: String,
     pub whitelisted_domains: DomainMatcher,
+    /// Model ID, e.g. `gemini-2.0-flash-exp`
+    #[serde(default)]
+    pub model: Option<String>,
+    /// API version, e.g. `v1beta`
+    #[serde(default)]
+    pub api_version: Option<String>,
+    #[serde(default)]
+    pub generation: GenerationSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// Oldest channel messages are dropped until a request fits this many tokens
+    #[serde(default)]
+    pub context_token_budget: Option<u32>,
+    /// Attachments up to this many bytes are sent inline instead of uploaded, `0` disables
+    #[serde(default = "default_inline_attachment_limit")]
+    pub inline_attachment_limit: u64,
+    /// Most attachment bytes inlined into one request, the rest is uploaded
+    #[serde(default = "default_inline_request_limit")]
+    pub inline_request_limit: u64,
+    /// Cache the system instruction and older history per channel, off when absent
+    #[serde(default)]
+    pub cache: Option<CacheSettings>,
+    /// Summarize messages dropped for the token budget, off when absent
+    #[serde(default)]
+    pub summary: Option<SummarySettings>,
+}
+
+impl GeminiSettings {
+    /// Catch settings that load fine but can't work together
+    fn validate(&self) -> Result<(), String> {
+        if self.summary.is_some() && self.context_token_budget.is_none() {
+            return Err("gemini.summary needs gemini.context_token_budget, \
+                        only messages dropped to fit it are summarized"
+                .into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Context caching through `cachedContents`
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub ttl_secs: u64,
+    /// Newest messages sent normally, the cache is rebuilt once twice as many piled up
+    pub uncached_messages: usize,
+    /// Smaller prefixes aren't cached, the API has a model-specific minimum
+    pub min_tokens: u32,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 60 * 60,
+            uncached_messages: 16,
+            min_tokens: 4096,
+        }
+    }
+}
+
+/// Rolling per-channel summary of messages that no longer fit the context
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SummarySettings {
+    /// Directory holding one file per channel
+    pub path: PathBuf,
+    /// Dropped messages collected before they are folded into the summary
+    pub batch_messages: usize,
+    /// Requested summary length, room for it is kept free in the token budget
+    pub max_words: usize,
+}
+
+impl Default for SummarySettings {
+    fn default() -> Self {
+        Self {
+            path: "summaries".into(),
+            batch_messages: 10,
+            max_words: 300,
+        }
+    }
+}
+
+const fn default_inline_attachment_limit() -> u64 {
+    512 * 1024
+}
+
+/// Leaves room for base64 overhead and text below the 20 MB request limit
+const fn default_inline_request_limit() -> u64 {
+    8 * 1024 * 1024
+}
+
+/// Client-side limits, requests over them wait in a queue
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct RateLimitSettings {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+impl RateLimitSettings {
+    pub fn to_limits(self) -> RateLimits {
+        RateLimits {
+            requests_per_minute: self.requests_per_minute,
+            tokens_per_minute: self.tokens_per_minute,
+            max_concurrent_requests: self.max_concurrent_requests,
+        }
+    }
+}
+
+/// Sampling parameters, anything unset is left to the model's defaults
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GenerationSettings {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<u32>,
+    pub max_output_tokens: Option<u32>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub seed: Option<i32>,
+    pub thinking_budget: Option<u32>,
+}
+
+impl GenerationSettings {
+    pub fn to_config(&self) -> GeminiGenerationConfig {
+        GeminiGenerationConfig {
+            stop_sequences: self.stop_sequences.clone(),
+            max_output_tokens: self.max_output_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            presence_penalty: self.presence_penalty,
+            frequency_penalty: self.frequency_penalty,
+            seed: self.seed,
+            thinking_config: self
+                .thinking_budget
+                .map(|thinking_budget| GeminiThinkingConfig {
+                    thinking_budget: Some(thinking_budget),
+                    ..Default::default()
+                }),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
     pub discord: DiscordSettings,
     pub gemini: GeminiSettings,
+    /// Long-term memory over past messages, off when absent
+    #[serde(default)]
+    pub memory: Option<MemorySettings>,
+}
+
+/// Persistent per-guild message embeddings
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MemorySettings {
+    /// Directory holding one file per guild
+    pub path: PathBuf,
+    /// Most past messages added to a request
+    pub top_k: usize,
+    /// Least cosine similarity for a past message to count as relevant
+    pub min_score: f32,
+}
+
+impl Default for MemorySettings {
+    fn default() -> Self {
+        Self {
+            path: "memory".into(),
+            top_k: 5,
+            min_score: 0.6,
+        }
+    }
 }
 
 fn deserialize_personality<'de, D>(deserializer: D) -> Result<String, D::Error>
@@ -102,8 +514,19 @@ where
     fs::read_to_string(path).map_err(Error::custom)
 }
 
-pub fn try_load() -> figment::Result<Settings> {
-    Figment::new().merge(Toml::file("Clyde.toml")).extract()
+fn deserialize_optional_personality<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_personality(deserializer).map(Some)
+}
+
+pub fn try_load() -> Result<Settings, Box<figment::Error>> {
+    let settings: Settings = Figment::new().merge(Toml::file("Clyde.toml")).extract()?;
+
+    settings.gemini.validate().map_err(figment::Error::from)?;
+
+    Ok(settings)
 }
 
 #[cfg(test)]
@@ -166,6 +589,132 @@ mod tests {
         assert!(whitelist.domain_matches("cdn.whatever.DISCORD.coM"));
     }
 
+    #[test]
+    fn generation_settings() {
+        let settings: GenerationSettings = Figment::new()
+            .merge(Toml::string(
+                "temperature = 1.5\ntop_k = 20\nstop_sequences = [\"bye\"]\nthinking_budget = 0",
+            ))
+            .extract()
+            .unwrap();
+
+        let config = settings.to_config();
+
+        assert_eq!(config.temperature, Some(1.5));
+        assert_eq!(config.top_k, Some(20));
+        assert_eq!(config.top_p, None);
+        assert_eq!(config.stop_sequences, ["bye"]);
+        assert_eq!(config.thinking_config.unwrap().thinking_budget, Some(0));
+    }
+
+    #[test]
+    fn summary_needs_budget() {
+        let gemini = |extra: &str| {
+            Figment::new()
+                .merge(Toml::string(&format!(
+                    "api_key = \"\"\nwhitelisted_domains = []\n{extra}"
+                )))
+                .extract::<GeminiSettings>()
+                .unwrap()
+        };
+
+        assert!(gemini("").validate().is_ok());
+        assert!(gemini("[summary]").validate().is_err());
+        assert!(gemini("context_token_budget = 1000\n[summary]")
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn trigger_overrides() {
+        let settings: DiscordSettings = Figment::new()
+            .merge(Toml::string(
+                "token = \"\"\n\
+                 [triggers]\nnames = [\"cleo\"]\nroles = [1]\n\
+                 [triggers.guilds.10]\nnames = [\"clyde\"]\neveryone = false\n\
+                 attachments = { policy = \"probability\", probability = 0.25 }\n\
+                 [channels.20.triggers]\neveryone = true\nattachments = { policy = \"always\" }",
+            ))
+            .extract()
+            .unwrap();
+
+        let default = settings.triggers(None, 30);
+
+        assert!(default.names.is_match("hi CLEO"));
+        assert_eq!(default.roles, [1]);
+        assert_eq!(default.attachments, AttachmentTrigger::Off);
+        assert!(default.everyone && default.replies);
+
+        let guild = settings.triggers(Some(10), 30);
+
+        assert!(guild.names.is_match("hi clyde") && !guild.names.is_match("hi cleo"));
+        assert_eq!(guild.roles, [1]);
+        assert_eq!(
+            guild.attachments,
+            AttachmentTrigger::Probability { probability: 0.25 }
+        );
+        assert!(!guild.everyone);
+
+        let channel = settings.triggers(Some(10), 20);
+
+        assert!(channel.names.is_match("hi clyde"));
+        assert_eq!(channel.attachments, AttachmentTrigger::Always);
+        assert!(channel.everyone);
+    }
+
+    #[test]
+    fn moved_channel_triggers() {
+        let error = Figment::new()
+            .merge(Toml::string(
+                "token = \"\"\n[triggers.channels.20]\neveryone = false",
+            ))
+            .extract::<DiscordSettings>()
+            .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("[discord.channels.<id>.triggers]"));
+    }
+
+    #[test]
+    fn channel_settings() {
+        let settings: DiscordSettings = Figment::new()
+            .merge(Toml::string(
+                "token = \"\"\ndenied_guilds = [2]\nallowed_channels = [20, 21]\n\
+                 denied_channels = [21]\n\
+                 [channels.20]\nmodel = \"gemini-1.5-pro\"\nactions = [\"SendMessage\"]",
+            ))
+            .extract()
+            .unwrap();
+
+        assert!(settings.is_allowed(Some(1), 20));
+        assert!(settings.is_allowed(None, 20));
+        assert!(!settings.is_allowed(Some(2), 20));
+        assert!(!settings.is_allowed(Some(1), 21));
+        assert!(!settings.is_allowed(Some(1), 22));
+
+        let channel = settings.channel(20).unwrap();
+
+        assert_eq!(channel.model.as_deref(), Some("gemini-1.5-pro"));
+        assert_eq!(channel.actions.as_deref(), Some(&[Action::SendMessage][..]));
+        assert!(channel.personality.is_none());
+        assert!(settings.channel(21).is_none());
+        assert!(!settings.is_dm_allowed(5));
+    }
+
+    #[test]
+    fn direct_messages() {
+        let settings: DiscordSettings = Figment::new()
+            .merge(Toml::string(
+                "token = \"\"\n[direct_messages]\nallowed_users = [5]",
+            ))
+            .extract()
+            .unwrap();
+
+        assert!(settings.is_dm_allowed(5));
+        assert!(!settings.is_dm_allowed(6));
+    }
+
     #[test]
     #[should_panic]
     fn domain_matcher_case_insensitive_unicode() {
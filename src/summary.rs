@@ -0,0 +1,304 @@
+use crate::lock::ChannelLocks;
+use crate::settings::SummarySettings;
+use anyhow::Context as _;
+use google_gemini::{
+    estimate_tokens, GeminiClient, GeminiMessage, GeminiRequest, GeminiRole, GeminiSystemPart, Part,
+};
+use serde::{Deserialize, Serialize};
+use serenity::all::ChannelId;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Anything that can fold messages into a running summary
+pub trait Summarizer {
+    async fn summarize(
+        &self,
+        summary: &str,
+        messages: &[GeminiMessage],
+        max_words: usize,
+    ) -> anyhow::Result<String>;
+}
+
+impl Summarizer for GeminiClient {
+    async fn summarize(
+        &self,
+        summary: &str,
+        messages: &[GeminiMessage],
+        max_words: usize,
+    ) -> anyhow::Result<String> {
+        let mut prompt = format!(
+            "Update the running summary of a Discord conversation with the messages below. \
+             Keep names, decisions, open questions and running jokes, drop small talk. \
+             Answer with the new summary only, at most {max_words} words.\n\n\
+             Current summary:\n{}\n\nNew messages:",
+            if summary.is_empty() {
+                "(none)"
+            } else {
+                summary
+            },
+        );
+
+        for text in messages.iter().flat_map(texts) {
+            prompt.push('\n');
+            prompt.push_str(text);
+        }
+
+        let request = GeminiRequest {
+            contents: vec![GeminiMessage::new(
+                GeminiRole::User,
+                vec![Part::from(prompt)],
+            )],
+            ..Default::default()
+        };
+
+        let summary = self.generate(request).await?.text();
+
+        anyhow::ensure!(!summary.trim().is_empty(), "empty summary");
+
+        Ok(summary.trim().to_string())
+    }
+}
+
+fn texts(message: &GeminiMessage) -> impl Iterator<Item = &str> {
+    message.parts.iter().filter_map(|part| match part {
+        Part::Text(text) => Some(text.text.as_str()),
+        Part::TaggedPart(_) => None,
+    })
+}
+
+/// Running summary of a channel, persisted as JSON
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct ChannelSummary {
+    text: String,
+    /// ID of the newest message folded into `text`
+    last: Option<u64>,
+}
+
+/// Rolling per-channel summaries of messages that no longer fit the context
+pub struct Summaries {
+    settings: SummarySettings,
+    channels: ChannelLocks<ChannelSummary>,
+}
+
+impl Summaries {
+    pub fn new(settings: SummarySettings) -> Self {
+        Self {
+            settings,
+            channels: ChannelLocks::default(),
+        }
+    }
+
+    /// Most tokens a summary takes up in a request, a word is rarely more than two
+    pub fn max_tokens(&self) -> u32 {
+        let words = u32::try_from(self.settings.max_words).unwrap_or(u32::MAX);
+
+        estimate_tokens(system_part("").text.as_bytes()).saturating_add(words.saturating_mul(2))
+    }
+
+    fn path(&self, channel_id: ChannelId) -> PathBuf {
+        self.settings.path.join(format!("{channel_id}.json"))
+    }
+
+    async fn load(&self, channel_id: ChannelId) -> anyhow::Result<ChannelSummary> {
+        match fs::read(self.path(channel_id)).await {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("reading summary of {channel_id}")),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(ChannelSummary::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn store(&self, channel_id: ChannelId, summary: &ChannelSummary) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.settings.path).await?;
+        fs::write(self.path(channel_id), serde_json::to_vec(summary)?).await?;
+
+        Ok(())
+    }
+
+    /// Fold newly `dropped` messages into the summary, once enough piled up
+    ///
+    /// `dropped` are the messages cut from the context with their IDs, oldest first.
+    /// Returns the current summary, empty if there is none yet.
+    pub async fn update(
+        &self,
+        summarizer: &impl Summarizer,
+        channel_id: ChannelId,
+        dropped: &[(u64, GeminiMessage)],
+    ) -> String {
+        let mut channel = self.channels.lock(channel_id).await;
+
+        let summary = match &mut *channel {
+            Some(summary) => summary,
+            None => match self.load(channel_id).await {
+                Ok(summary) => channel.insert(summary),
+                Err(error) => {
+                    tracing::warn!("failed to load summary: {error:#}");
+
+                    return String::new();
+                }
+            },
+        };
+
+        let new = unsummarized(summary.last, dropped);
+
+        if new.is_empty() || new.len() < self.settings.batch_messages {
+            return summary.text.clone();
+        }
+
+        let messages = new
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect::<Vec<_>>();
+
+        match summarizer
+            .summarize(&summary.text, &messages, self.settings.max_words)
+            .await
+        {
+            Ok(text) => {
+                tracing::debug!("summarized {} messages of {channel_id}", new.len());
+
+                summary.text = text;
+                summary.last = new.last().map(|(message_id, _)| *message_id);
+
+                if let Err(error) = self.store(channel_id, summary).await {
+                    tracing::warn!("failed to store summary of {channel_id}: {error:#}");
+                }
+            }
+            Err(error) => tracing::warn!("failed to summarize {channel_id}: {error:#}"),
+        }
+
+        summary.text.clone()
+    }
+}
+
+/// Dropped messages newer than the last summarized one
+fn unsummarized(last: Option<u64>, dropped: &[(u64, GeminiMessage)]) -> &[(u64, GeminiMessage)] {
+    let start = last.map_or(0, |last| {
+        dropped.partition_point(|(message_id, _)| *message_id <= last)
+    });
+
+    &dropped[start..]
+}
+
+/// System instruction part carrying `summary`
+pub fn system_part(summary: &str) -> GeminiSystemPart {
+    GeminiSystemPart {
+        text: format!("Summary of the earlier conversation in this channel:\n{summary}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    /// Joins every message into the summary, recording what it was asked
+    #[derive(Default)]
+    struct JoinSummarizer {
+        calls: StdMutex<Vec<(String, usize)>>,
+    }
+
+    impl Summarizer for JoinSummarizer {
+        async fn summarize(
+            &self,
+            summary: &str,
+            messages: &[GeminiMessage],
+            _max_words: usize,
+        ) -> anyhow::Result<String> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push((summary.to_string(), messages.len()));
+
+            let mut parts = vec![summary];
+
+            parts.extend(messages.iter().flat_map(texts));
+
+            Ok(parts.join(" ").trim().to_string())
+        }
+    }
+
+    fn messages(ids: &[u64]) -> Vec<(u64, GeminiMessage)> {
+        ids.iter()
+            .map(|id| {
+                let message =
+                    GeminiMessage::new(GeminiRole::User, vec![Part::from(id.to_string())]);
+
+                (*id, message)
+            })
+            .collect()
+    }
+
+    fn summaries(name: &str) -> Summaries {
+        let path =
+            std::env::temp_dir().join(format!("claide-summary-{name}-{}", std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&path);
+
+        Summaries::new(SummarySettings {
+            path,
+            batch_messages: 2,
+            max_words: 100,
+        })
+    }
+
+    #[tokio::test]
+    async fn rolling_summary() {
+        let summaries = summaries("rolling");
+        let summarizer = JoinSummarizer::default();
+        let channel_id = ChannelId::new(1);
+
+        // too few to bother yet
+        let summary = summaries
+            .update(&summarizer, channel_id, &messages(&[1]))
+            .await;
+
+        assert!(summary.is_empty());
+
+        let summary = summaries
+            .update(&summarizer, channel_id, &messages(&[1, 2]))
+            .await;
+
+        assert_eq!(summary, "1 2");
+
+        // fewer dropped this time, all of them summarized already
+        let summary = summaries
+            .update(&summarizer, channel_id, &messages(&[1]))
+            .await;
+
+        assert_eq!(summary, "1 2");
+
+        // 1 scrolled out of the cache, 2 was summarized already
+        let summary = summaries
+            .update(&summarizer, channel_id, &messages(&[2, 3]))
+            .await;
+
+        assert_eq!(summary, "1 2");
+
+        let summary = summaries
+            .update(&summarizer, channel_id, &messages(&[2, 3, 4]))
+            .await;
+
+        assert_eq!(summary, "1 2 3 4");
+        assert_eq!(
+            *summarizer.calls.lock().unwrap(),
+            [(String::new(), 2), ("1 2".into(), 2)]
+        );
+
+        let other = summaries.update(&summarizer, ChannelId::new(2), &[]).await;
+
+        assert!(other.is_empty());
+
+        // survives restarts
+        let restarted = Summaries::new(summaries.settings.clone());
+        let summary = restarted
+            .update(&summarizer, channel_id, &messages(&[3, 4]))
+            .await;
+
+        assert_eq!(summary, "1 2 3 4");
+        assert_eq!(summarizer.calls.lock().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&summaries.settings.path).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use crate::settings::{AttachmentTrigger, Triggers};
+use serenity::all::{Message, UserId};
+
+/// Whether `message` should be answered by `current_user_id`
+pub fn is_triggered(triggers: &Triggers, message: &Message, current_user_id: UserId) -> bool {
+    message
+        .mentions
+        .iter()
+        .any(|user| user.id == current_user_id)
+        || triggers.names.is_match(&message.content)
+        || message
+            .mention_roles
+            .iter()
+            .any(|role| triggers.roles.contains(&role.get()))
+        || (triggers.everyone && message.mention_everyone)
+        || (triggers.replies && is_reply(message, current_user_id))
+        || (!message.attachments.is_empty() && attachments_trigger(&triggers.attachments, message))
+}
+
+/// Whether `message` replies to one of `current_user_id`'s messages
+pub fn is_reply(message: &Message, current_user_id: UserId) -> bool {
+    message
+        .referenced_message
+        .as_ref()
+        .is_some_and(|referenced| referenced.author.id == current_user_id)
+}
+
+fn attachments_trigger(policy: &AttachmentTrigger, message: &Message) -> bool {
+    match policy {
+        AttachmentTrigger::Off => false,
+        AttachmentTrigger::Always => true,
+        AttachmentTrigger::Channels { channels } => channels.contains(&message.channel_id.get()),
+        AttachmentTrigger::WithName { names } => names.is_match(&message.content),
+        AttachmentTrigger::Probability { probability } => fastrand::f64() < *probability,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::NameMatcher;
+    use serenity::all::{ChannelId, RoleId, User};
+
+    const BOT: UserId = UserId::new(1234);
+
+    fn message(content: &str) -> Message {
+        let mut message = Message::default();
+
+        message.content = content.into();
+        message
+    }
+
+    fn user(id: UserId) -> User {
+        let mut user = User::default();
+
+        user.id = id;
+        user
+    }
+
+    #[test]
+    fn names_and_roles() {
+        let triggers = Triggers {
+            names: NameMatcher::new(["cleo"]).unwrap(),
+            roles: vec![7],
+            ..Default::default()
+        };
+
+        assert!(is_triggered(&triggers, &message("hi CLEO"), BOT));
+        assert!(!is_triggered(&triggers, &message("hi clyde"), BOT));
+
+        let mut mention = message("hi");
+
+        mention.mention_roles = vec![RoleId::new(7)];
+        assert!(is_triggered(&triggers, &mention, BOT));
+
+        mention.mention_roles = vec![RoleId::new(8)];
+        assert!(!is_triggered(&triggers, &mention, BOT));
+
+        mention.mentions = vec![user(BOT)];
+        assert!(is_triggered(&Triggers::default(), &mention, BOT));
+    }
+
+    #[test]
+    fn flags() {
+        let all = Triggers::default();
+        let none = Triggers {
+            everyone: false,
+            replies: false,
+            ..Default::default()
+        };
+
+        let mut everyone = message("hi all");
+
+        everyone.mention_everyone = true;
+        assert!(is_triggered(&all, &everyone, BOT));
+        assert!(!is_triggered(&none, &everyone, BOT));
+
+        let mut reply = message("thanks");
+        let mut referenced = message("hello");
+
+        referenced.author = user(BOT);
+        reply.referenced_message = Some(Box::new(referenced));
+        assert!(is_triggered(&all, &reply, BOT));
+        assert!(!is_triggered(&none, &reply, BOT));
+
+        let mut other_reply = message("thanks");
+
+        other_reply.referenced_message = Some(Box::new(message("hello")));
+        assert!(!is_triggered(&all, &other_reply, BOT));
+    }
+
+    fn with_attachment(mut message: Message) -> Message {
+        let attachment = serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "filename": "meme.png",
+            "size": 4,
+            "url": "https://cdn.discordapp.com/meme.png",
+            "proxy_url": "https://media.discordapp.net/meme.png",
+        }))
+        .unwrap();
+
+        message.attachments.push(attachment);
+        message
+    }
+
+    #[test]
+    fn attachment_policies() {
+        let mut message = with_attachment(message("look"));
+
+        message.channel_id = ChannelId::new(5);
+
+        let policy = |policy| attachments_trigger(&policy, &message);
+
+        assert!(!policy(AttachmentTrigger::Off));
+        assert!(policy(AttachmentTrigger::Always));
+        assert!(policy(AttachmentTrigger::Channels { channels: vec![5] }));
+        assert!(!policy(AttachmentTrigger::Channels { channels: vec![6] }));
+        assert!(!policy(AttachmentTrigger::Probability { probability: 0.0 }));
+        assert!(policy(AttachmentTrigger::Probability { probability: 1.0 }));
+    }
+
+    #[test]
+    fn attachments_with_name() {
+        let triggers = Triggers {
+            attachments: AttachmentTrigger::WithName {
+                names: NameMatcher::new(["cleo"]).unwrap(),
+            },
+            ..Default::default()
+        };
+
+        assert!(is_triggered(
+            &triggers,
+            &with_attachment(message("cleo look at this")),
+            BOT
+        ));
+        // the name alone doesn't trigger, only alongside an attachment
+        assert!(!is_triggered(&triggers, &message("cleo look at this"), BOT));
+        assert!(!is_triggered(
+            &triggers,
+            &with_attachment(message("look at this")),
+            BOT
+        ));
+        assert!(!is_triggered(
+            &Triggers::default(),
+            &with_attachment(message("cleo look at this")),
+            BOT
+        ));
+    }
+}